@@ -1,13 +1,120 @@
 use std::result;
-use super::redis_value::{Node, Value};
+use std::time::Duration;
+use std::str::{self, FromStr};
+use super::redis_value::{parse_int, Node, Value};
 
 pub type Result<T> = result::Result<T, ParseError>;
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Cmd<T> {
-    SET { key: T, value: T },
+    SET {
+        key: T,
+        value: T,
+        expire: Option<Duration>,
+        condition: Option<SetCondition>,
+    },
+    MSET { pairs: Vec<(T, T)> },
+    SETNX { key: T, value: T },
+    GETSET { key: T, value: T },
+    RENAME { key: T, newkey: T },
+    RENAMENX { key: T, newkey: T },
     GET { key: T },
+    GETDEL { key: T },
+    STRLEN { key: T },
+    GETRANGE { key: T, start: i64, end: i64 },
+    SETRANGE { key: T, offset: i64, value: T },
+    TYPE { key: T },
+    LPUSH { key: T, values: Vec<T> },
+    RPUSH { key: T, values: Vec<T> },
+    LRANGE { key: T, start: i64, end: i64 },
+    LPOP { key: T, count: Option<usize> },
+    RPOP { key: T, count: Option<usize> },
+    HSET { key: T, pairs: Vec<(T, T)> },
+    HGET { key: T, field: T },
+    HDEL { key: T, fields: Vec<T> },
+    HGETALL { key: T },
+    HKEYS { key: T },
+    HVALS { key: T },
+    SADD { key: T, members: Vec<T> },
+    SREM { key: T, members: Vec<T> },
+    SMEMBERS { key: T },
+    SISMEMBER { key: T, member: T },
+    SCARD { key: T },
+    SINTER { keys: Vec<T> },
     DEL { keys: Vec<T> },
+    MGET { keys: Vec<T> },
+    EXISTS { keys: Vec<T> },
+    KEYS { pattern: T },
+    EXPIRE { key: T, expire: Duration },
+    TTL { key: T },
+    PERSIST { key: T },
+    DBSIZE,
+    PING { message: Option<T> },
+    LOLWUT,
+    ECHO { message: T },
+    FLUSHDB,
+    WAIT,
+    MONITOR,
+    CLIENT { subcommand: ClientCmd },
+    OBJECT { subcommand: Object, key: T },
+    DEBUG { subcommand: DebugCmd },
+    LATENCY { subcommand: Latency },
+    INCR { key: T },
+    DECR { key: T },
+    APPEND { key: T, value: T },
+    INCRBY { key: T, delta: i64 },
+    DECRBY { key: T, delta: i64 },
+    INFO { section: Option<T> },
+    MEMORY { subcommand: Memory },
+    SCAN {
+        cursor: u64,
+        count: usize,
+    },
+    COMMAND { subcommand: Command<T> },
+}
+
+#[derive(Debug)]
+pub enum Command<T> {
+    All,
+    Info(Vec<T>),
+}
+
+// SET NX / SET XX
+#[derive(Debug)]
+pub enum SetCondition {
+    NotExists,
+    Exists,
+}
+
+#[derive(Debug)]
+pub enum Object {
+    Encoding,
+}
+
+#[derive(Debug)]
+pub enum ClientCmd {
+    Id,
+}
+
+#[derive(Debug)]
+pub enum DebugCmd {
+    Noop,
+    Digest,
+    // turns the background expiry sweep off (false) or back on
+    SetActiveExpire(bool),
+}
+
+#[derive(Debug)]
+pub enum Memory {
+    Stats,
+}
+
+#[derive(Debug)]
+pub enum Latency {
+    History,
+    Latest,
+    Reset,
 }
 
 #[derive(Debug)]
@@ -17,15 +124,468 @@ pub enum ParseError {
     ExtraValues,
     EmptyNodes,
     NilError,
+    NotImplemented,
+    NotInteger,
+    InvalidExpireTime,
+    TooManyArgs,
+    // a malformed option list, e.g. SET with both NX and XX
+    Syntax,
     UnknownError,
 }
 
+// default upper bound on the number of arguments of any command
+const DEFAULT_MAX_ARGS: usize = 1024 * 1024;
+
+// Per-command limits on the number of arguments (command name excluded),
+// checked as soon as the command name is read so oversized commands are
+// rejected before their arguments are collected
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArgLimits {
+    default: usize,
+    commands: Vec<(Vec<u8>, usize)>,
+}
+
+impl ArgLimits {
+    pub fn new(default: usize) -> Self {
+        ArgLimits {
+            default,
+            commands: Vec::new(),
+        }
+    }
+    pub fn set_default(&mut self, max_args: usize) {
+        self.default = max_args;
+    }
+    // replaces any earlier limit for command
+    pub fn with_limit(mut self, command: &[u8], max_args: usize) -> Self {
+        self.commands.retain(|(name, _)| !name.eq_ignore_ascii_case(command));
+        self.commands.push((command.to_ascii_uppercase(), max_args));
+        self
+    }
+    fn max_args(&self, command: &[u8]) -> usize {
+        self.commands
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(command))
+            .map_or(self.default, |&(_, max_args)| max_args)
+    }
+}
+
+impl Default for ArgLimits {
+    fn default() -> Self {
+        ArgLimits::new(DEFAULT_MAX_ARGS)
+    }
+}
+
+// Commands recognized by name which this build does not implement (yet),
+// these get a distinct error reply instead of an unknown command one
+const NOT_IMPLEMENTED: &[&[u8]] = &[
+    b"MULTI",
+    b"EXEC",
+    b"DISCARD",
+    b"WATCH",
+    b"UNWATCH",
+    b"SUBSCRIBE",
+    b"UNSUBSCRIBE",
+    b"PSUBSCRIBE",
+    b"PUNSUBSCRIBE",
+    b"PUBLISH",
+    b"EVAL",
+    b"EVALSHA",
+    b"SCRIPT",
+    b"SAVE",
+    b"BGSAVE",
+    b"BGREWRITEAOF",
+    b"SYNC",
+    b"PSYNC",
+    b"REPLICAOF",
+    b"SLAVEOF",
+];
+
+fn is_not_implemented(keyword: &[u8]) -> bool {
+    NOT_IMPLEMENTED
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(keyword))
+}
+
+// DEBUG subcommands commonly issued by client test suites, accepted (with
+// any arguments) and answered with OK without doing anything
+pub const DEBUG_NOOPS: &[&[u8]] = &[
+    b"CHANGE-REPL-ID",
+    b"SET-SKIP-CHECKSUM-VALIDATION",
+    b"SET-DISABLE-DENY-SCRIPTS",
+    b"QUICKLIST-PACKED-THRESHOLD",
+    b"STRINGMATCH-LEN",
+    b"DICT-RESIZING",
+    b"REPLYBUFFER",
+    b"PAUSE-CRON",
+];
+
+// What COMMAND reports about a command: arity counts the command name and
+// is negative for "at least", keys is (first key, last key, step) as
+// argument positions, last key -1 meaning the final argument
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    pub keys: (i64, i64, i64),
+}
+
+// one entry per implemented command
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "GET",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "SET",
+        arity: -3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "SETNX",
+        arity: 3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "GETSET",
+        arity: 3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "GETDEL",
+        arity: 2,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "MSET",
+        arity: -3,
+        flags: &["write"],
+        keys: (1, -1, 2),
+    },
+    CommandSpec {
+        name: "MGET",
+        arity: -2,
+        flags: &["readonly"],
+        keys: (1, -1, 1),
+    },
+    CommandSpec {
+        name: "STRLEN",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "GETRANGE",
+        arity: 4,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "SUBSTR",
+        arity: 4,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "SETRANGE",
+        arity: 4,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "APPEND",
+        arity: 3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "INCR",
+        arity: 2,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "DECR",
+        arity: 2,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "INCRBY",
+        arity: 3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "DECRBY",
+        arity: 3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "LPUSH",
+        arity: -3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "RPUSH",
+        arity: -3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "LRANGE",
+        arity: 4,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "LPOP",
+        arity: -2,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "RPOP",
+        arity: -2,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "HSET",
+        arity: -4,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "HGET",
+        arity: 3,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "HDEL",
+        arity: -3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "HGETALL",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "HKEYS",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "HVALS",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "SADD",
+        arity: -3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "SREM",
+        arity: -3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "SMEMBERS",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "SISMEMBER",
+        arity: 3,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "SCARD",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "SINTER",
+        arity: -2,
+        flags: &["readonly"],
+        keys: (1, -1, 1),
+    },
+    CommandSpec {
+        name: "DEL",
+        arity: -2,
+        flags: &["write"],
+        keys: (1, -1, 1),
+    },
+    CommandSpec {
+        name: "EXISTS",
+        arity: -2,
+        flags: &["readonly"],
+        keys: (1, -1, 1),
+    },
+    CommandSpec {
+        name: "TYPE",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "RENAME",
+        arity: 3,
+        flags: &["write"],
+        keys: (1, 2, 1),
+    },
+    CommandSpec {
+        name: "RENAMENX",
+        arity: 3,
+        flags: &["write"],
+        keys: (1, 2, 1),
+    },
+    CommandSpec {
+        name: "KEYS",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "SCAN",
+        arity: -2,
+        flags: &["readonly"],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "EXPIRE",
+        arity: 3,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "TTL",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "PERSIST",
+        arity: 2,
+        flags: &["write"],
+        keys: (1, 1, 1),
+    },
+    CommandSpec {
+        name: "DBSIZE",
+        arity: 1,
+        flags: &["readonly"],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "FLUSHDB",
+        arity: 1,
+        flags: &["write"],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "OBJECT",
+        arity: 3,
+        flags: &["readonly"],
+        keys: (2, 2, 1),
+    },
+    CommandSpec {
+        name: "INFO",
+        arity: -1,
+        flags: &[],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "MEMORY",
+        arity: 2,
+        flags: &["readonly"],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "LATENCY",
+        arity: -2,
+        flags: &["admin"],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "DEBUG",
+        arity: -2,
+        flags: &["admin"],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "WAIT",
+        arity: 3,
+        flags: &[],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "MONITOR",
+        arity: 1,
+        flags: &["admin"],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "CLIENT",
+        arity: -2,
+        flags: &["admin"],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "PING",
+        arity: -1,
+        flags: &[],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "ECHO",
+        arity: 2,
+        flags: &[],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "LOLWUT",
+        arity: -1,
+        flags: &["readonly"],
+        keys: (0, 0, 0),
+    },
+    CommandSpec {
+        name: "COMMAND",
+        arity: -1,
+        flags: &[],
+        keys: (0, 0, 0),
+    },
+];
+
+pub fn command_spec(name: &[u8]) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+}
+
 enum ParserState<T> {
     Start,
     Started(usize),
-    ParseSET(Option<T>, Option<T>),
-    ParseGET(Option<T>),
-    ParseDEL(usize, Vec<T>),
+    // number of items still expected, items read so far (command name first)
+    Collect(usize, Vec<T>),
     Done(Cmd<T>),
     Error(ParseError),
 }
@@ -34,86 +594,478 @@ impl<T> ParserState<Value<T>>
 where
     T: AsRef<[u8]>,
 {
-    fn next_node(self, node: Node<T>) -> Self {
+    fn next_node(self, node: Node<T>, limits: &ArgLimits) -> Self {
         use self::ParserState::*;
 
         match self {
             Start => match node {
-                Node::Open(x) if x >= 2 => Started(x),
+                Node::Open(x) if x >= 1 => Started(x),
                 _ => Error(ParseError::EmptyNodes),
             },
             Started(n_items) => match node {
+                Node::Leaf(Value::Nil) => Error(ParseError::NilError),
+                Node::Leaf(ref v) if n_items - 1 > limits.max_args(v.as_slice()) => {
+                    Error(ParseError::TooManyArgs)
+                }
                 Node::Leaf(v) => {
-                    let keyword: &[u8] = v.as_slice();
-                    match keyword {
-                        b"SET" if n_items == 3 => ParseSET(None, None),
-                        b"GET" if n_items == 2 => ParseGET(None),
-                        b"DEL" => ParseDEL(n_items - 1, Vec::with_capacity(n_items - 1)),
-                        _ => Error(ParseError::UnknownCmd),
-                    }
+                    let mut items = Vec::with_capacity(n_items);
+                    items.push(v);
+                    Collect(n_items - 1, items)
                 }
                 _ => Error(ParseError::Unexpected),
             },
-            ParseGET(None) => match node {
-                Node::Leaf(v) => ParseGET(Some(v)),
-                _ => Error(ParseError::Unexpected),
-            },
-            ParseGET(Some(v)) => match &v {
-                &Value::Nil => Error(ParseError::NilError),
-                _ => match node {
-                    Node::Close => Done(Cmd::GET { key: v }),
-                    _ => Error(ParseError::ExtraValues),
+            Collect(0, items) => match node {
+                Node::Close => match Cmd::from_args(items) {
+                    Ok(cmd) => Done(cmd),
+                    Err(e) => Error(e),
                 },
+                _ => Error(ParseError::ExtraValues),
             },
-            ParseSET(None, None) => match node {
-                Node::Leaf(v) => ParseSET(Some(v), None),
+            Collect(n_expected, mut items) => match node {
+                Node::Leaf(Value::Nil) => Error(ParseError::NilError),
+                Node::Leaf(v) => {
+                    items.push(v);
+                    Collect(n_expected - 1, items)
+                }
                 _ => Error(ParseError::Unexpected),
             },
-            ParseSET(Some(k), None) => match node {
-                Node::Leaf(v) => ParseSET(Some(k), Some(v)),
-                _ => Error(ParseError::Unexpected),
+            Error(e) => Error(e),
+            Done(_) => Error(ParseError::ExtraValues),
+        }
+    }
+}
+
+impl<T> Cmd<T> {
+    // The keys a command reads or writes
+    pub fn keys(&self) -> Vec<&T> {
+        match self {
+            Cmd::SET { key, .. }
+            | Cmd::SETNX { key, .. }
+            | Cmd::GETSET { key, .. }
+            | Cmd::GET { key }
+            | Cmd::GETDEL { key }
+            | Cmd::STRLEN { key }
+            | Cmd::GETRANGE { key, .. }
+            | Cmd::SETRANGE { key, .. }
+            | Cmd::TYPE { key }
+            | Cmd::LPUSH { key, .. }
+            | Cmd::RPUSH { key, .. }
+            | Cmd::LRANGE { key, .. }
+            | Cmd::LPOP { key, .. }
+            | Cmd::RPOP { key, .. }
+            | Cmd::HSET { key, .. }
+            | Cmd::HGET { key, .. }
+            | Cmd::HDEL { key, .. }
+            | Cmd::HGETALL { key }
+            | Cmd::HKEYS { key }
+            | Cmd::HVALS { key }
+            | Cmd::SADD { key, .. }
+            | Cmd::SREM { key, .. }
+            | Cmd::SMEMBERS { key }
+            | Cmd::SISMEMBER { key, .. }
+            | Cmd::SCARD { key }
+            | Cmd::OBJECT { key, .. }
+            | Cmd::INCR { key }
+            | Cmd::DECR { key }
+            | Cmd::APPEND { key, .. }
+            | Cmd::INCRBY { key, .. }
+            | Cmd::DECRBY { key, .. }
+            | Cmd::EXPIRE { key, .. }
+            | Cmd::TTL { key }
+            | Cmd::PERSIST { key } => vec![key],
+            Cmd::RENAME { key, newkey } | Cmd::RENAMENX { key, newkey } => vec![key, newkey],
+            Cmd::MSET { pairs } => pairs.iter().map(|(key, _)| key).collect(),
+            Cmd::DEL { keys }
+            | Cmd::MGET { keys }
+            | Cmd::EXISTS { keys }
+            | Cmd::SINTER { keys } => keys.iter().collect(),
+            Cmd::KEYS { .. }
+            | Cmd::SCAN { .. }
+            | Cmd::DBSIZE
+            | Cmd::FLUSHDB
+            | Cmd::WAIT
+            | Cmd::MONITOR
+            | Cmd::CLIENT { .. }
+            | Cmd::PING { .. }
+            | Cmd::ECHO { .. }
+            | Cmd::LOLWUT
+            | Cmd::COMMAND { .. }
+            | Cmd::INFO { .. }
+            | Cmd::MEMORY { .. }
+            | Cmd::DEBUG { .. }
+            | Cmd::LATENCY { .. } => vec![],
+        }
+    }
+}
+
+impl<T> Cmd<Value<T>>
+where
+    T: AsRef<[u8]>,
+{
+    // args holds the full command array, command name first
+    fn from_args(args: Vec<Value<T>>) -> Result<Self> {
+        let n_args = args.len();
+        let mut args = args.into_iter();
+        let keyword = next_arg(&mut args)?.as_slice().to_ascii_uppercase();
+
+        let cmd = match (&keyword[..], n_args) {
+            (b"SET", n) if n >= 3 => {
+                let key = next_arg(&mut args)?;
+                let value = next_arg(&mut args)?;
+                let mut expire = None;
+                let mut condition = None;
+                while let Some(option) = args.next() {
+                    let option = option.as_slice().to_ascii_uppercase();
+                    match &option[..] {
+                        b"EX" | b"PX" if expire.is_none() => {
+                            let n = args.next().ok_or(ParseError::Syntax)?;
+                            let n = parse_expire(n.as_slice())?;
+                            expire = Some(if option == b"EX" {
+                                Duration::from_secs(n)
+                            } else {
+                                Duration::from_millis(n)
+                            });
+                        }
+                        b"NX" if condition.is_none() => condition = Some(SetCondition::NotExists),
+                        b"XX" if condition.is_none() => condition = Some(SetCondition::Exists),
+                        _ => return Err(ParseError::Syntax),
+                    }
+                }
+                Cmd::SET {
+                    key,
+                    value,
+                    expire,
+                    condition,
+                }
+            }
+            (b"SETNX", 3) => Cmd::SETNX {
+                key: next_arg(&mut args)?,
+                value: next_arg(&mut args)?,
             },
-            ParseSET(Some(k), Some(v)) => match node {
-                Node::Close => match (&k, &v) {
-                    (&Value::Nil, _) => Error(ParseError::NilError),
-                    (_, &Value::Nil) => Error(ParseError::NilError),
-                    _ => Done(Cmd::SET { key: k, value: v }),
-                },
-                _ => Error(ParseError::Unexpected),
+            (b"GETSET", 3) => Cmd::GETSET {
+                key: next_arg(&mut args)?,
+                value: next_arg(&mut args)?,
             },
-            ParseDEL(n_expected, mut xs) => {
-                if n_expected > 0 {
-                    match node {
-                        Node::Leaf(v) => {
-                            xs.push(v);
-                            ParseDEL(n_expected - 1, xs)
-                        }
-                        _ => Error(ParseError::Unexpected),
+            (b"RENAME", 3) => Cmd::RENAME {
+                key: next_arg(&mut args)?,
+                newkey: next_arg(&mut args)?,
+            },
+            (b"RENAMENX", 3) => Cmd::RENAMENX {
+                key: next_arg(&mut args)?,
+                newkey: next_arg(&mut args)?,
+            },
+            (b"MSET", n) if n >= 3 && n % 2 == 1 => {
+                let mut pairs = Vec::with_capacity(n / 2);
+                while let Some(key) = args.next() {
+                    pairs.push((key, next_arg(&mut args)?));
+                }
+                Cmd::MSET { pairs }
+            }
+            (b"GET", 2) => Cmd::GET {
+                key: next_arg(&mut args)?,
+            },
+            (b"APPEND", 3) => Cmd::APPEND {
+                key: next_arg(&mut args)?,
+                value: next_arg(&mut args)?,
+            },
+            (b"GETDEL", 2) => Cmd::GETDEL {
+                key: next_arg(&mut args)?,
+            },
+            (b"STRLEN", 2) => Cmd::STRLEN {
+                key: next_arg(&mut args)?,
+            },
+            (b"KEYS", 2) => Cmd::KEYS {
+                pattern: next_arg(&mut args)?,
+            },
+            (b"PING", 1) => Cmd::PING { message: None },
+            (b"PING", 2) => Cmd::PING {
+                message: args.next(),
+            },
+            (b"ECHO", 2) => Cmd::ECHO {
+                message: next_arg(&mut args)?,
+            },
+            (b"LOLWUT", 1) => Cmd::LOLWUT,
+            // the requested art version is accepted but there's only one
+            (b"LOLWUT", 3) => {
+                let option = next_arg(&mut args)?.as_slice().to_ascii_uppercase();
+                if option != b"VERSION" {
+                    return Err(ParseError::UnknownCmd);
+                }
+                parse_i64(next_arg(&mut args)?.as_slice())?;
+                Cmd::LOLWUT
+            }
+            (b"EXPIRE", 3) => Cmd::EXPIRE {
+                key: next_arg(&mut args)?,
+                expire: Duration::from_secs(parse_expire(next_arg(&mut args)?.as_slice())?),
+            },
+            (b"TTL", 2) => Cmd::TTL {
+                key: next_arg(&mut args)?,
+            },
+            (b"PERSIST", 2) => Cmd::PERSIST {
+                key: next_arg(&mut args)?,
+            },
+            (b"DBSIZE", 1) => Cmd::DBSIZE,
+            (b"FLUSHDB", 1) => Cmd::FLUSHDB,
+            // there are no replicas, so both counts are checked and dropped
+            (b"WAIT", 3) => {
+                parse_i64(next_arg(&mut args)?.as_slice())?;
+                parse_i64(next_arg(&mut args)?.as_slice())?;
+                Cmd::WAIT
+            }
+            (b"MONITOR", 1) => Cmd::MONITOR,
+            (b"CLIENT", 2) => {
+                let subcommand = next_arg(&mut args)?.as_slice().to_ascii_uppercase();
+                let subcommand = match &subcommand[..] {
+                    b"ID" => ClientCmd::Id,
+                    _ => return Err(ParseError::UnknownCmd),
+                };
+                Cmd::CLIENT { subcommand }
+            }
+            // SUBSTR is the old name of GETRANGE
+            (b"GETRANGE", 4) | (b"SUBSTR", 4) => Cmd::GETRANGE {
+                key: next_arg(&mut args)?,
+                start: parse_i64(next_arg(&mut args)?.as_slice())?,
+                end: parse_i64(next_arg(&mut args)?.as_slice())?,
+            },
+            (b"SETRANGE", 4) => Cmd::SETRANGE {
+                key: next_arg(&mut args)?,
+                offset: parse_i64(next_arg(&mut args)?.as_slice())?,
+                value: next_arg(&mut args)?,
+            },
+            (b"TYPE", 2) => Cmd::TYPE {
+                key: next_arg(&mut args)?,
+            },
+            (b"LPUSH", n) if n >= 3 => Cmd::LPUSH {
+                key: next_arg(&mut args)?,
+                values: args.collect(),
+            },
+            (b"RPUSH", n) if n >= 3 => Cmd::RPUSH {
+                key: next_arg(&mut args)?,
+                values: args.collect(),
+            },
+            (b"LRANGE", 4) => Cmd::LRANGE {
+                key: next_arg(&mut args)?,
+                start: parse_i64(next_arg(&mut args)?.as_slice())?,
+                end: parse_i64(next_arg(&mut args)?.as_slice())?,
+            },
+            (b"LPOP", 2) | (b"LPOP", 3) => Cmd::LPOP {
+                key: next_arg(&mut args)?,
+                count: parse_count(args.next())?,
+            },
+            (b"RPOP", 2) | (b"RPOP", 3) => Cmd::RPOP {
+                key: next_arg(&mut args)?,
+                count: parse_count(args.next())?,
+            },
+            (b"HSET", n) if n >= 4 && n % 2 == 0 => {
+                let key = next_arg(&mut args)?;
+                let mut pairs = Vec::with_capacity(n / 2 - 1);
+                while let Some(field) = args.next() {
+                    pairs.push((field, next_arg(&mut args)?));
+                }
+                Cmd::HSET { key, pairs }
+            }
+            (b"HGET", 3) => Cmd::HGET {
+                key: next_arg(&mut args)?,
+                field: next_arg(&mut args)?,
+            },
+            (b"HDEL", n) if n >= 3 => Cmd::HDEL {
+                key: next_arg(&mut args)?,
+                fields: args.collect(),
+            },
+            (b"HGETALL", 2) => Cmd::HGETALL {
+                key: next_arg(&mut args)?,
+            },
+            (b"HKEYS", 2) => Cmd::HKEYS {
+                key: next_arg(&mut args)?,
+            },
+            (b"HVALS", 2) => Cmd::HVALS {
+                key: next_arg(&mut args)?,
+            },
+            (b"SADD", n) if n >= 3 => Cmd::SADD {
+                key: next_arg(&mut args)?,
+                members: args.collect(),
+            },
+            (b"SREM", n) if n >= 3 => Cmd::SREM {
+                key: next_arg(&mut args)?,
+                members: args.collect(),
+            },
+            (b"SMEMBERS", 2) => Cmd::SMEMBERS {
+                key: next_arg(&mut args)?,
+            },
+            (b"SISMEMBER", 3) => Cmd::SISMEMBER {
+                key: next_arg(&mut args)?,
+                member: next_arg(&mut args)?,
+            },
+            (b"SCARD", 2) => Cmd::SCARD {
+                key: next_arg(&mut args)?,
+            },
+            (b"SINTER", n) if n >= 2 => Cmd::SINTER {
+                keys: args.collect(),
+            },
+            (b"INCR", 2) => Cmd::INCR {
+                key: next_arg(&mut args)?,
+            },
+            (b"DECR", 2) => Cmd::DECR {
+                key: next_arg(&mut args)?,
+            },
+            (b"INCRBY", 3) => Cmd::INCRBY {
+                key: next_arg(&mut args)?,
+                delta: parse_i64(next_arg(&mut args)?.as_slice())?,
+            },
+            (b"DECRBY", 3) => Cmd::DECRBY {
+                key: next_arg(&mut args)?,
+                delta: parse_i64(next_arg(&mut args)?.as_slice())?,
+            },
+            (b"DEL", n) if n >= 2 => Cmd::DEL {
+                keys: args.collect(),
+            },
+            (b"MGET", n) if n >= 2 => Cmd::MGET {
+                keys: args.collect(),
+            },
+            (b"EXISTS", n) if n >= 2 => Cmd::EXISTS {
+                keys: args.collect(),
+            },
+            (b"OBJECT", 3) => {
+                let subcommand = next_arg(&mut args)?.as_slice().to_ascii_uppercase();
+                let subcommand = match &subcommand[..] {
+                    b"ENCODING" => Object::Encoding,
+                    _ => return Err(ParseError::UnknownCmd),
+                };
+                Cmd::OBJECT {
+                    subcommand,
+                    key: next_arg(&mut args)?,
+                }
+            }
+            (b"DEBUG", n) if n >= 2 => {
+                let subcommand = next_arg(&mut args)?;
+                let subcommand = subcommand.as_slice();
+                if n == 2 && subcommand.eq_ignore_ascii_case(b"DIGEST") {
+                    Cmd::DEBUG {
+                        subcommand: DebugCmd::Digest,
+                    }
+                } else if n == 3 && subcommand.eq_ignore_ascii_case(b"SET-ACTIVE-EXPIRE") {
+                    let enabled = parse_i64(next_arg(&mut args)?.as_slice())? != 0;
+                    Cmd::DEBUG {
+                        subcommand: DebugCmd::SetActiveExpire(enabled),
+                    }
+                } else if DEBUG_NOOPS
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(subcommand))
+                {
+                    Cmd::DEBUG {
+                        subcommand: DebugCmd::Noop,
                     }
                 } else {
-                    match node {
-                        Node::Close => Done(Cmd::DEL { keys: xs }),
-                        _ => Error(ParseError::ExtraValues),
+                    return Err(ParseError::UnknownCmd);
+                }
+            }
+            (b"LATENCY", n) if n >= 2 => {
+                let subcommand = next_arg(&mut args)?.as_slice().to_ascii_uppercase();
+                let subcommand = match (&subcommand[..], n) {
+                    (b"HISTORY", 3) => Latency::History,
+                    (b"LATEST", 2) => Latency::Latest,
+                    (b"RESET", _) => Latency::Reset,
+                    _ => return Err(ParseError::UnknownCmd),
+                };
+                Cmd::LATENCY { subcommand }
+            }
+            (b"INFO", 1) => Cmd::INFO { section: None },
+            (b"INFO", 2) => Cmd::INFO {
+                section: args.next(),
+            },
+            (b"MEMORY", 2) => {
+                let subcommand = next_arg(&mut args)?.as_slice().to_ascii_uppercase();
+                let subcommand = match &subcommand[..] {
+                    b"STATS" => Memory::Stats,
+                    _ => return Err(ParseError::UnknownCmd),
+                };
+                Cmd::MEMORY { subcommand }
+            }
+            (b"SCAN", n) if n % 2 == 0 => {
+                let cursor = parse_uint(next_arg(&mut args)?.as_slice())?;
+                let mut count = DEFAULT_SCAN_COUNT;
+                while let Some(option) = args.next() {
+                    let option = option.as_slice().to_ascii_uppercase();
+                    match &option[..] {
+                        b"COUNT" => {
+                            count = parse_uint(next_arg(&mut args)?.as_slice())? as usize;
+                        }
+                        _ => return Err(ParseError::UnknownCmd),
                     }
                 }
+                Cmd::SCAN { cursor, count }
             }
-            Error(e) => Error(e),
-            Done(_) => Error(ParseError::ExtraValues),
-            _ => Error(ParseError::UnknownError),
-        }
+            (b"COMMAND", 1) => Cmd::COMMAND {
+                subcommand: Command::All,
+            },
+            (b"COMMAND", _) => {
+                let subcommand = next_arg(&mut args)?.as_slice().to_ascii_uppercase();
+                let subcommand = match &subcommand[..] {
+                    b"INFO" => Command::Info(args.collect()),
+                    _ => return Err(ParseError::UnknownCmd),
+                };
+                Cmd::COMMAND { subcommand }
+            }
+            (keyword, _) if is_not_implemented(keyword) => {
+                return Err(ParseError::NotImplemented)
+            }
+            _ => return Err(ParseError::UnknownCmd),
+        };
+
+        Ok(cmd)
+    }
+}
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+fn parse_i64(arg: &[u8]) -> Result<i64> {
+    parse_int(arg).ok_or(ParseError::NotInteger)
+}
+
+// expire times must be positive
+fn parse_expire(arg: &[u8]) -> Result<u64> {
+    match parse_i64(arg)? {
+        n if n > 0 => Ok(n as u64),
+        _ => Err(ParseError::InvalidExpireTime),
     }
 }
 
+fn parse_uint(arg: &[u8]) -> Result<u64> {
+    str::from_utf8(arg)
+        .ok()
+        .and_then(|s| u64::from_str(s).ok())
+        .ok_or(ParseError::NotInteger)
+}
+
+// an optional trailing count argument, as in LPOP key [count]
+fn parse_count<T: AsRef<[u8]>>(arg: Option<Value<T>>) -> Result<Option<usize>> {
+    match arg {
+        Some(arg) => Ok(Some(parse_uint(arg.as_slice())? as usize)),
+        None => Ok(None),
+    }
+}
+
+fn next_arg<T, I: Iterator<Item = T>>(args: &mut I) -> Result<T> {
+    args.next().ok_or(ParseError::Unexpected)
+}
+
+#[cfg(test)]
 pub fn parse_command<T: AsRef<[u8]>, I: IntoIterator<Item = Node<T>>>(
     iter: I,
+) -> Result<Cmd<Value<T>>> {
+    parse_command_with_limits(iter, &ArgLimits::default())
+}
+
+pub fn parse_command_with_limits<T: AsRef<[u8]>, I: IntoIterator<Item = Node<T>>>(
+    iter: I,
+    limits: &ArgLimits,
 ) -> Result<Cmd<Value<T>>> {
     let mut state = ParserState::Start;
 
     for node in iter.into_iter() {
-        state = state.next_node(node);
-        match state {
-            ParserState::Error(e) => return Err(e),
-            _ => (),
+        state = state.next_node(node, limits);
+        if let ParserState::Error(e) = state {
+            return Err(e);
         }
     }
 
@@ -124,3 +1076,122 @@ pub fn parse_command<T: AsRef<[u8]>, I: IntoIterator<Item = Node<T>>>(
         _ => Err(ParseError::UnknownError),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::redis_value::RedisValue;
+
+    fn parse(raw: &str) -> Result<Cmd<Value<::bytes::Bytes>>> {
+        let (_, value) = RedisValue::decode(&raw).unwrap().unwrap();
+        parse_command(value.nodes)
+    }
+
+    fn parse_args(args: &[&str]) -> Result<Cmd<Value<::bytes::Bytes>>> {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        parse_command(RedisValue::from_slices(&args).nodes)
+    }
+
+    #[test]
+    fn test_parse_set_options() {
+        assert_matches!(
+            parse_args(&["SET", "k", "v"]),
+            Ok(Cmd::SET {
+                expire: None,
+                condition: None,
+                ..
+            })
+        );
+        match parse_args(&["set", "k", "v", "ex", "10", "NX"]) {
+            Ok(Cmd::SET {
+                expire: Some(expire),
+                condition: Some(SetCondition::NotExists),
+                ..
+            }) => assert_eq!(expire, Duration::from_secs(10)),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_args(&["SET", "k", "v", "XX", "PX", "1500"]) {
+            Ok(Cmd::SET {
+                expire: Some(expire),
+                condition: Some(SetCondition::Exists),
+                ..
+            }) => assert_eq!(expire, Duration::from_millis(1500)),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert_matches!(
+            parse_args(&["SET", "k", "v", "EX", "0"]),
+            Err(ParseError::InvalidExpireTime)
+        );
+        assert_matches!(
+            parse_args(&["SET", "k", "v", "EX", "-5"]),
+            Err(ParseError::InvalidExpireTime)
+        );
+        assert_matches!(parse_args(&["SET", "k", "v", "EX", "ten"]), Err(ParseError::NotInteger));
+        assert_matches!(parse_args(&["SET", "k", "v", "EX"]), Err(ParseError::Syntax));
+        assert_matches!(parse_args(&["SET", "k", "v", "NX", "XX"]), Err(ParseError::Syntax));
+        assert_matches!(
+            parse_args(&["SET", "k", "v", "EX", "1", "PX", "1"]),
+            Err(ParseError::Syntax)
+        );
+        assert_matches!(parse_args(&["SET", "k", "v", "KEEPTTL"]), Err(ParseError::Syntax));
+    }
+
+    #[test]
+    fn test_parse_pop_count() {
+        assert_matches!(parse_args(&["LPOP", "l"]), Ok(Cmd::LPOP { count: None, .. }));
+        assert_matches!(parse_args(&["RPOP", "l", "2"]), Ok(Cmd::RPOP { count: Some(2), .. }));
+        assert_matches!(parse_args(&["LPOP", "l", "-1"]), Err(ParseError::NotInteger));
+        assert_matches!(parse_args(&["RPOP", "l", "two"]), Err(ParseError::NotInteger));
+        assert_matches!(parse_args(&["LPOP", "l", "1", "2"]), Err(ParseError::UnknownCmd));
+    }
+
+    #[test]
+    fn test_parse_wait() {
+        assert_matches!(parse_args(&["WAIT", "1", "100"]), Ok(Cmd::WAIT));
+        assert_matches!(parse_args(&["WAIT", "1", "soon"]), Err(ParseError::NotInteger));
+        assert_matches!(parse_args(&["WAIT", "1"]), Err(ParseError::UnknownCmd));
+    }
+
+    #[test]
+    fn test_parse_not_implemented() {
+        assert_matches!(parse("*1\r\n$5\r\nMULTI\r\n"), Err(ParseError::NotImplemented));
+        assert_matches!(
+            parse("*2\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n"),
+            Err(ParseError::NotImplemented)
+        );
+        assert_matches!(parse("*1\r\n$5\r\nNOPE!\r\n"), Err(ParseError::UnknownCmd));
+        assert_matches!(parse("*1\r\n$3\r\nDEL\r\n"), Err(ParseError::UnknownCmd));
+    }
+
+    #[test]
+    fn test_parse_arg_limits() {
+        let limits = ArgLimits::default().with_limit(b"del", 2);
+        let parse_limited = |raw: &str| {
+            let (_, value) = RedisValue::decode(&raw).unwrap().unwrap();
+            parse_command_with_limits(value.nodes, &limits)
+        };
+
+        assert_matches!(
+            parse_limited("*3\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n"),
+            Ok(Cmd::DEL { .. })
+        );
+        assert_matches!(
+            parse_limited("*4\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n"),
+            Err(ParseError::TooManyArgs)
+        );
+        assert_matches!(
+            parse("*4\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n"),
+            Ok(Cmd::DEL { .. })
+        );
+
+        let limits = ArgLimits::new(1);
+        let (_, value) = RedisValue::decode(&"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nb\r\n")
+            .unwrap()
+            .unwrap();
+        assert_matches!(
+            parse_command_with_limits(value.nodes, &limits),
+            Err(ParseError::TooManyArgs)
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use super::commands::ArgLimits;
+use super::protocol::OutputLimits;
+use super::redis_value::DecodeLimits;
+
+// Server settings, read from an optional redis.conf style file (one
+// "name value" directive per line) and then overridden by command line
+// flags of the same names, as in `toy-redis redis.conf --port 7000`
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    pub output_limits: OutputLimits,
+    pub decode_limits: DecodeLimits,
+    pub arg_limits: ArgLimits,
+    // read and kept, but not enforced yet: no eviction, AUTH or SELECT
+    pub maxmemory: u64,
+    pub requirepass: Option<String>,
+    pub databases: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "127.0.0.1".to_string(),
+            port: 6379,
+            output_limits: OutputLimits::default(),
+            decode_limits: DecodeLimits::default(),
+            arg_limits: ArgLimits::default(),
+            // no limit, no password and 16 databases, as in redis
+            maxmemory: 0,
+            requirepass: None,
+            databases: 16,
+        }
+    }
+}
+
+impl Config {
+    // args excludes the program name
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> io::Result<Self> {
+        let mut config = Config::default();
+        let mut args = args.into_iter().peekable();
+
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            config.load(BufReader::new(File::open(path)?))?;
+        }
+        while let Some(flag) = args.next() {
+            let name = match flag.strip_prefix("--") {
+                Some(name) => name.to_string(),
+                None => {
+                    let msg = format!("unexpected argument {}", flag);
+                    return Err(io_error!(InvalidInput, msg));
+                }
+            };
+            match args.next() {
+                Some(value) => config.set(&name, &value)?,
+                None => {
+                    let msg = format!("missing value for {}", flag);
+                    return Err(io_error!(InvalidInput, msg));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn load<R: BufRead>(&mut self, reader: R) -> io::Result<()> {
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let name = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            self.set(name, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn addr(&self) -> io::Result<SocketAddr> {
+        let ip: IpAddr = self
+            .bind
+            .parse()
+            .map_err(|_| io_error!(InvalidInput, format!("invalid bind address {}", self.bind)))?;
+        Ok(SocketAddr::new(ip, self.port))
+    }
+
+    // only the settings listed here are accepted, anything else (save,
+    // appendonly, ...) is an error rather than silently ignored
+    fn set(&mut self, name: &str, value: &str) -> io::Result<()> {
+        match &name.to_ascii_lowercase()[..] {
+            "bind" => self.bind = value.to_string(),
+            "port" => {
+                self.port = value
+                    .parse()
+                    .map_err(|_| io_error!(InvalidInput, format!("invalid port {}", value)))?
+            }
+            "client-output-buffer-limit" => self.output_limits = parse_output_limits(value)?,
+            "proto-max-bulk-len" => {
+                self.decode_limits.max_bulk_len = value
+                    .parse()
+                    .map_err(|_| io_error!(InvalidInput, format!("invalid length {}", value)))?
+            }
+            "command-max-args" => parse_arg_limit(value, &mut self.arg_limits)?,
+            "maxmemory" => self.maxmemory = parse_memory(value)?,
+            // an empty password turns authentication off
+            "requirepass" if value.is_empty() => self.requirepass = None,
+            "requirepass" => self.requirepass = Some(value.to_string()),
+            "databases" => {
+                self.databases = match value.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        let msg = format!("invalid number of databases {}", value);
+                        return Err(io_error!(InvalidInput, msg));
+                    }
+                }
+            }
+            _ => {
+                let msg = format!("unsupported config directive {}", name);
+                return Err(io_error!(InvalidInput, msg));
+            }
+        }
+        Ok(())
+    }
+}
+
+// A byte count with an optional unit, as redis reads memory settings:
+// k, m and g are powers of 1000, kb, mb and gb powers of 1024
+fn parse_memory(value: &str) -> io::Result<u64> {
+    let invalid = || io_error!(InvalidInput, format!("invalid memory size {}", value));
+    let value = value.to_ascii_lowercase();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(invalid()),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    number.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+// "<n>" for the default limit, "<command> <n>" for one command's, which
+// overrides the default
+fn parse_arg_limit(value: &str, limits: &mut ArgLimits) -> io::Result<()> {
+    let invalid = || io_error!(InvalidInput, format!("invalid argument limit {}", value));
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    match parts[..] {
+        [max_args] => limits.set_default(max_args.parse().map_err(|_| invalid())?),
+        [command, max_args] => {
+            let max_args = max_args.parse().map_err(|_| invalid())?;
+            *limits = mem::take(limits).with_limit(command.as_bytes(), max_args);
+        }
+        _ => return Err(invalid()),
+    }
+    Ok(())
+}
+
+// "normal <hard bytes> <soft bytes> <soft seconds>", the only client class
+// there is here
+fn parse_output_limits(value: &str) -> io::Result<OutputLimits> {
+    let invalid = || io_error!(InvalidInput, format!("invalid output buffer limit {}", value));
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    match parts[..] {
+        [class, hard, soft, seconds] if class.eq_ignore_ascii_case("normal") => {
+            Ok(OutputLimits {
+                hard: hard.parse().map_err(|_| invalid())?,
+                soft: soft.parse().map_err(|_| invalid())?,
+                soft_duration: Duration::from_secs(seconds.parse().map_err(|_| invalid())?),
+            })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::env;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_load_config() {
+        let mut config = Config::default();
+        let file = b"# test config\n\nbind 0.0.0.0\nPORT   7000\n\
+                     client-output-buffer-limit normal 1024 512 10\n\
+                     proto-max-bulk-len 4096\n\
+                     command-max-args 100\ncommand-max-args del 1\ncommand-max-args DEL 2\n\
+                     maxmemory 100mb\nrequirepass s3cret\ndatabases 4\n";
+        config.load(&file[..]).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                bind: "0.0.0.0".to_string(),
+                port: 7000,
+                output_limits: OutputLimits {
+                    hard: 1024,
+                    soft: 512,
+                    soft_duration: Duration::from_secs(10),
+                },
+                decode_limits: DecodeLimits {
+                    max_bulk_len: 4096,
+                    ..DecodeLimits::default()
+                },
+                arg_limits: ArgLimits::new(100).with_limit(b"del", 2),
+                maxmemory: 100 * 1024 * 1024,
+                requirepass: Some("s3cret".to_string()),
+                databases: 4,
+            }
+        );
+        assert_eq!(config.addr().unwrap(), "0.0.0.0:7000".parse().unwrap());
+
+        assert!(config.load(&b"port seventy\n"[..]).is_err());
+        assert!(config.load(&b"appendonly yes\n"[..]).is_err());
+        assert!(config.load(&b"maxmemory 100tb\n"[..]).is_err());
+        assert!(config.load(&b"maxmemory -1\n"[..]).is_err());
+        assert!(config.load(&b"databases 0\n"[..]).is_err());
+        assert!(config.load(&b"command-max-args del\n"[..]).is_err());
+        assert!(config.load(&b"command-max-args del 1 2\n"[..]).is_err());
+        assert!(config.load(&b"proto-max-bulk-len -1\n"[..]).is_err());
+        assert!(config.load(&b"client-output-buffer-limit pubsub 1 1 1\n"[..]).is_err());
+        assert!(config.load(&b"client-output-buffer-limit normal 1 1\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("0").unwrap(), 0);
+        assert_eq!(parse_memory("1024").unwrap(), 1024);
+        assert_eq!(parse_memory("2b").unwrap(), 2);
+        assert_eq!(parse_memory("1k").unwrap(), 1000);
+        assert_eq!(parse_memory("1KB").unwrap(), 1024);
+        assert_eq!(parse_memory("3m").unwrap(), 3_000_000);
+        assert_eq!(parse_memory("3Mb").unwrap(), 3 * 1024 * 1024);
+        assert_eq!(parse_memory("1g").unwrap(), 1_000_000_000);
+        assert_eq!(parse_memory("1gb").unwrap(), 1024 * 1024 * 1024);
+        for invalid in &["", "mb", "1.5gb", "1 gb", "-1", "99999999999999999999gb"] {
+            assert!(parse_memory(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_from_args() {
+        assert_eq!(Config::from_args(vec![]).unwrap(), Config::default());
+
+        let path = env::temp_dir().join(format!("toy-redis-test-{}.conf", std::process::id()));
+        fs::write(&path, "bind 0.0.0.0\nport 7000\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let config = Config::from_args(args(&[path])).unwrap();
+        assert_eq!(config.addr().unwrap(), "0.0.0.0:7000".parse().unwrap());
+        // flags override the file
+        let config = Config::from_args(args(&[path, "--port", "7001"])).unwrap();
+        assert_eq!(config.addr().unwrap(), "0.0.0.0:7001".parse().unwrap());
+        let config = Config::from_args(args(&[path, "--maxmemory", "1gb"])).unwrap();
+        assert_eq!(config.maxmemory, 1024 * 1024 * 1024);
+        fs::remove_file(path).unwrap();
+
+        let config = Config::from_args(args(&["--bind", "::1"])).unwrap();
+        assert_eq!(config.addr().unwrap(), "[::1]:6379".parse().unwrap());
+        let config = Config::from_args(args(&["--bind", "localhost"])).unwrap();
+        assert!(config.addr().is_err());
+        assert!(Config::from_args(args(&["--port"])).is_err());
+        assert!(Config::from_args(args(&["--port", "1", "extra"])).is_err());
+        assert!(Config::from_args(args(&["/nonexistent/toy-redis.conf"])).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 extern crate bytes;
-#[macro_use]
+#[cfg_attr(test, macro_use)]
 extern crate matches;
 extern crate stringreader;
 
@@ -13,24 +13,57 @@ extern crate tokio_service;
 mod macros;
 mod redis_value;
 mod commands;
+mod clock;
 mod store;
 mod protocol;
 mod service;
+mod monitor;
+mod config;
+mod stats;
 
+use std::env;
+use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio_proto::TcpServer;
 
 use protocol::RedisProto;
 use store::Store;
 use service::RedisService;
+use monitor::Monitors;
+use config::Config;
 
 fn main() {
-    // Specify the localhost address
-    let addr = "127.0.0.1:6379".parse().unwrap();
+    // Settings from an optional config file and command line flags
+    let config = Config::from_args(env::args().skip(1));
+    let (config, addr) = match config.and_then(|c| c.addr().map(|addr| (c, addr))) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("toy-redis: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // read from the config but not acted on, say so rather than let a
+    // password or memory limit look like it's in force
+    if config.requirepass.is_some() {
+        eprintln!("toy-redis: warning: requirepass is set but AUTH is not supported");
+    }
+    if config.maxmemory > 0 {
+        eprintln!("toy-redis: warning: maxmemory is set but keys are never evicted");
+    }
 
     // The builder requires a protocol and an address
-    let server = TcpServer::new(RedisProto, addr);
-    let store = Arc::new(Store::new());
+    let server = TcpServer::new(RedisProto::new(config.output_limits, config.decode_limits), addr);
+    // expired keys are evicted in the background ten times a second
+    let store = Store::with_expiry_sweep(Duration::from_millis(100));
+    // connections in MONITOR mode, fed the commands of every connection
+    let monitors = Arc::new(Monitors::new());
+
+    let arg_limits = Arc::new(config.arg_limits);
 
-    server.serve(move || Ok(RedisService::new(store.clone())));
+    server.serve(move || {
+        let service = RedisService::with_monitors(store.clone(), monitors.clone());
+        Ok(service.with_arg_limits(arg_limits.clone()))
+    });
 }
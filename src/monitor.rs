@@ -0,0 +1,146 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use futures::sync::mpsc::{self, Receiver, Sender};
+
+use super::redis_value::{RedisValue, Value};
+
+// Frames pushed to a connection outside of request/reply. The transport
+// takes the next one only once the previous ones are written, so a reader
+// too slow to keep up leaves them queued here, and once MONITOR_BUFFER
+// are queued it's unsubscribed: its connection closes after writing what
+// was queued, rather than memory growing with the backlog
+pub type Feed = Receiver<RedisValue>;
+
+pub const MONITOR_BUFFER: usize = 1024;
+
+// The connections in MONITOR mode, each fed a line for every command run
+// by any connection
+pub struct Monitors {
+    senders: Mutex<Vec<Sender<RedisValue>>>,
+    // len of senders, so publishing with no monitors doesn't lock
+    active: AtomicUsize,
+}
+
+impl Monitors {
+    pub fn new() -> Self {
+        Monitors {
+            senders: Mutex::new(Vec::new()),
+            active: AtomicUsize::new(0),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.active.load(Ordering::Relaxed) == 0
+    }
+    pub fn subscribe(&self) -> Feed {
+        let (tx, rx) = mpsc::channel(MONITOR_BUFFER);
+        let mut senders = self.senders.lock().unwrap();
+        senders.push(tx);
+        self.active.store(senders.len(), Ordering::Relaxed);
+        rx
+    }
+    // Sends line to every monitor as a status reply, dropping monitors
+    // whose connection has gone away or whose buffer is full
+    pub fn publish(&self, line: String) {
+        let frame = Value::SimpleString(Bytes::from(line));
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain_mut(|tx| {
+            let frame = RedisValue::from_value(frame.clone());
+            tx.try_send(frame).is_ok()
+        });
+        self.active.store(senders.len(), Ordering::Relaxed);
+    }
+}
+
+// A command as MONITOR shows it, like redis:
+// 1339518083.107412 [0 127.0.0.1:60866] "SET" "k" "v"
+pub fn format_command(addr: Option<SocketAddr>, args: &[&[u8]]) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut line = format!("{}.{:06} [0 ", now.as_secs(), now.subsec_micros());
+    match addr {
+        Some(addr) => write!(line, "{}]", addr).unwrap(),
+        None => line.push_str("unknown]"),
+    }
+    for arg in args {
+        line.push(' ');
+        quote(arg, &mut line);
+    }
+    line
+}
+
+// Appends arg double quoted, escaped like redis' sdscatrepr
+fn quote(arg: &[u8], out: &mut String) {
+    out.push('"');
+    for &c in arg {
+        match c {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            7 => out.push_str("\\a"),
+            8 => out.push_str("\\b"),
+            c if c.is_ascii_graphic() || c == b' ' => out.push(c as char),
+            c => write!(out, "\\x{:02x}", c).unwrap(),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::redis_value::Node;
+    use futures::{Future, Stream};
+
+    #[test]
+    fn test_format_command() {
+        let addr = "127.0.0.1:6000".parse().ok();
+        let line = format_command(addr, &[b"SET", b"k", b"a \"b\"\\\n\x01"]);
+        let (time, rest) = line.split_at(line.find(' ').unwrap());
+        let (secs, micros) = time.split_at(time.find('.').unwrap());
+        assert!(secs.parse::<u64>().is_ok());
+        assert_eq!(micros.len(), 7);
+        assert_eq!(rest, r#" [0 127.0.0.1:6000] "SET" "k" "a \"b\"\\\n\x01""#);
+
+        assert!(format_command(None, &[b"PING"]).ends_with(r#" [0 unknown] "PING""#));
+    }
+
+    #[test]
+    fn test_publish_drops_closed_monitors() {
+        let monitors = Monitors::new();
+        assert!(monitors.is_empty());
+        let feed = monitors.subscribe();
+        drop(monitors.subscribe());
+        assert!(!monitors.is_empty());
+
+        monitors.publish("line".to_string());
+        assert_eq!(monitors.active.load(Ordering::Relaxed), 1);
+        drop(monitors);
+        let frames: Vec<RedisValue> = feed.collect().wait().unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_matches!(frames[0].nodes[0], Node::Leaf(Value::SimpleString(ref s)) if s == "line");
+    }
+
+    #[test]
+    fn test_publish_drops_full_monitors() {
+        let monitors = Monitors::new();
+        let feed = monitors.subscribe();
+        // the channel holds one more than its buffer per sender
+        for _ in 0..MONITOR_BUFFER + 1 {
+            monitors.publish("line".to_string());
+        }
+        assert!(!monitors.is_empty());
+        monitors.publish("line".to_string());
+        assert!(monitors.is_empty());
+        // what was queued is still delivered, then the feed ends
+        let frames: Vec<RedisValue> = feed.collect().wait().unwrap();
+        assert_eq!(frames.len(), MONITOR_BUFFER + 1);
+    }
+}
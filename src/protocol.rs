@@ -1,23 +1,293 @@
 use std::io;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
 
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use tokio_core::net::TcpStream;
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_io::codec::{Decoder, Encoder, Framed};
+use tokio_io::codec::{Decoder, Encoder};
 use tokio_proto::pipeline::ServerProto;
 
-use super::redis_value::RedisValue;
+use super::redis_value::{DecodeLimits, RedisValue, Value};
+use super::monitor::Feed;
+use super::stats::PROTOCOL_ERRORS;
 
-pub struct RedisCodec;
+// Id of the next accepted connection. Ids start at 1 and are never reused
+// while the process runs
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
 
-pub struct RedisProto;
+#[derive(Default)]
+pub struct RedisCodec {
+    limits: DecodeLimits,
+}
+
+// A decoded request and the connection it came in on
+pub struct Request {
+    pub frame: RedisValue,
+    pub client: Client,
+}
+
+// What's known of the connection a request came in on
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Client {
+    pub id: u64,
+    pub addr: Option<SocketAddr>,
+}
+
+// The frames written in reply to one request, in order: a single one for
+// most commands, several for ones like SUBSCRIBE (one per channel). A feed
+// keeps pushing frames to the connection afterwards (MONITOR)
+#[derive(Debug)]
+pub struct Reply {
+    pub frames: Vec<RedisValue>,
+    pub feed: Option<Feed>,
+}
+
+impl Reply {
+    pub fn new(frames: Vec<RedisValue>) -> Self {
+        Reply { frames, feed: None }
+    }
+}
+
+impl From<RedisValue> for Reply {
+    fn from(frame: RedisValue) -> Self {
+        Reply::new(vec![frame])
+    }
+}
+
+// The address of the other end of a connection, if it has one
+pub trait PeerAddr {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+pub struct RedisProto {
+    limits: OutputLimits,
+    decode_limits: DecodeLimits,
+}
+
+impl RedisProto {
+    pub fn new(limits: OutputLimits, decode_limits: DecodeLimits) -> Self {
+        RedisProto {
+            limits,
+            decode_limits,
+        }
+    }
+}
+
+// Limits on the reply bytes buffered for a client that isn't reading them,
+// like redis' client-output-buffer-limit. Going over hard closes the
+// connection at once, staying over soft for soft_duration closes it too.
+// 0 disables a limit, the default
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OutputLimits {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_duration: Duration,
+}
+
+// Framed, but with an unbounded write buffer checked against OutputLimits:
+// replies are always accepted, a client too slow to take them is dropped.
+// A malformed frame ends the stream of requests, and is answered with a
+// protocol error once the requests before it are
+pub struct RedisTransport<T> {
+    io: T,
+    codec: RedisCodec,
+    rd: BytesMut,
+    wr: BytesMut,
+    eof: bool,
+    limits: OutputLimits,
+    over_soft_since: Option<Instant>,
+    client: Client,
+    feed: Option<Feed>,
+    // requests passed on and not replied to yet
+    in_flight: usize,
+    protocol_error: Option<RedisValue>,
+}
+
+impl<T> RedisTransport<T> {
+    pub fn new(io: T, limits: OutputLimits, client: Client) -> Self {
+        RedisTransport {
+            io,
+            codec: RedisCodec::default(),
+            rd: BytesMut::new(),
+            wr: BytesMut::new(),
+            eof: false,
+            limits,
+            over_soft_since: None,
+            client,
+            feed: None,
+            in_flight: 0,
+            protocol_error: None,
+        }
+    }
+
+    // replies come in the order of the requests, so the protocol error
+    // goes out only once nothing before it is left to answer
+    fn write_protocol_error(&mut self) -> io::Result<()> {
+        if self.in_flight == 0 {
+            if let Some(reply) = self.protocol_error.take() {
+                self.codec.encode(reply, &mut self.wr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_output_limits(&mut self) -> io::Result<()> {
+        let pending = self.wr.len();
+        if self.limits.hard > 0 && pending > self.limits.hard {
+            return Err(io_error!(Other, "client output buffer hard limit reached"));
+        }
+        if self.limits.soft > 0 && pending > self.limits.soft {
+            let since = *self.over_soft_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= self.limits.soft_duration {
+                return Err(io_error!(Other, "client output buffer soft limit reached"));
+            }
+        } else {
+            self.over_soft_since = None;
+        }
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead> Stream for RedisTransport<T> {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Request>, io::Error> {
+        loop {
+            match self.codec.decode(&mut self.rd) {
+                Ok(Some(frame)) => {
+                    self.in_flight += 1;
+                    let client = self.client;
+                    return Ok(Async::Ready(Some(Request { frame, client })));
+                }
+                Ok(None) => {}
+                // like redis, the client is told why before the connection
+                // closes, and nothing it sent after the bad frame is read
+                Err(e) => {
+                    let reply = format!("ERR Protocol error: {}", e);
+                    self.protocol_error = Some(RedisValue::from_value(Value::from_error(&reply)));
+                    self.rd.clear();
+                    self.eof = true;
+                    self.write_protocol_error()?;
+                    return Ok(Async::Ready(None));
+                }
+            }
+            // frames already buffered when the client shut down its write
+            // half are still decoded and answered above, only then does the
+            // stream end
+            if self.eof {
+                if self.rd.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+                return Err(io_error!(Other, "connection closed mid frame"));
+            }
+
+            // room for at least one byte, so a 0 read really means EOF
+            self.rd.reserve(1);
+            match AsyncRead::read_buf(&mut self.io, &mut self.rd)? {
+                Async::Ready(0) => self.eof = true,
+                Async::Ready(_) => {}
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite> Sink for RedisTransport<T> {
+    type SinkItem = Reply;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Reply) -> StartSend<Reply, io::Error> {
+        for frame in item.frames {
+            self.codec.encode(frame, &mut self.wr)?;
+        }
+        self.in_flight = self.in_flight.saturating_sub(1);
+        self.write_protocol_error()?;
+        if item.feed.is_some() {
+            self.feed = item.feed;
+        }
+        self.check_output_limits()?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        loop {
+            while !self.wr.is_empty() {
+                match self.io.write(&self.wr) {
+                    Ok(0) => return Err(io_error!(WriteZero, "failed to write reply")),
+                    Ok(n) => {
+                        self.wr.advance(n);
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        self.check_output_limits()?;
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            // the feed is only taken from once everything before is written,
+            // so a slow reader's backlog stays in its bounded channel. Polling
+            // it also wakes this connection up when it has more
+            let frame = match self.feed {
+                Some(ref mut feed) => match feed.poll() {
+                    Ok(Async::Ready(Some(frame))) => frame,
+                    // unsubscribed for falling behind
+                    Ok(Async::Ready(None)) | Err(()) => {
+                        return Err(io_error!(Other, "monitor fell too far behind"));
+                    }
+                    Ok(Async::NotReady) => break,
+                },
+                None => break,
+            };
+            self.codec.encode(frame, &mut self.wr)?;
+        }
+        self.over_soft_since = None;
+        match self.io.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        match self.poll_complete()? {
+            Async::Ready(()) => self.io.shutdown(),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
 
 impl Decoder for RedisCodec {
     type Item = RedisValue;
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<RedisValue>, io::Error> {
-        RedisValue::decode(&*buf)
+        loop {
+            match buf.first() {
+                Some(b'*') | Some(b'$') | Some(b'+') | Some(b'-') | Some(b':') | None => break,
+                Some(_) => match self.decode_inline(buf)? {
+                    None => return Ok(None),
+                    // blank lines are skipped
+                    Some(ref args) if args.is_empty() => continue,
+                    Some(args) => {
+                        let args: Vec<&[u8]> = args.iter().map(|arg| &arg[..]).collect();
+                        return Ok(Some(RedisValue::from_slices(&args)));
+                    }
+                },
+            }
+        }
+        RedisValue::decode_with_limits(&*buf, &self.limits)
             .map(|redis_val| {
                 match redis_val {
                     Some((consumed, x)) => {
@@ -36,10 +306,124 @@ impl Decoder for RedisCodec {
                     None => None,
                 }
             })
-            .map_err(|_| io_error!(InvalidData, "RESP decode Error"))
+            .map_err(|reason| {
+                PROTOCOL_ERRORS.fetch_add(1, Ordering::Relaxed);
+                io_error!(InvalidData, reason)
+            })
+    }
+}
+
+// longest inline command accepted, like redis' PROTO_INLINE_MAX_SIZE
+const INLINE_MAX_SIZE: usize = 64 * 1024;
+
+impl RedisCodec {
+    // The arguments of a command typed as a single line of space separated
+    // words (as over telnet), None until the whole line is in. A line not
+    // ended within INLINE_MAX_SIZE bytes is an error, so it isn't buffered
+    // and searched again without limit
+    fn decode_inline(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<Vec<u8>>>, io::Error> {
+        let n = match buf.iter().take(INLINE_MAX_SIZE).position(|b| *b == b'\n') {
+            Some(n) => n,
+            None if buf.len() < INLINE_MAX_SIZE => return Ok(None),
+            None => {
+                PROTOCOL_ERRORS.fetch_add(1, Ordering::Relaxed);
+                return Err(io_error!(InvalidData, "too big inline request"));
+            }
+        };
+        let args = {
+            let line = &buf[..n];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            split_args(line)
+        };
+        buf.advance(n + 1);
+
+        match args {
+            Some(args) => Ok(Some(args)),
+            None => {
+                PROTOCOL_ERRORS.fetch_add(1, Ordering::Relaxed);
+                Err(io_error!(InvalidData, "unbalanced quotes in inline command"))
+            }
+        }
     }
 }
 
+// Splits an inline command into arguments like redis' sdssplitargs:
+// words are separated by runs of whitespace, and "..." or '...' quote
+// words containing spaces. Double quotes understand \n, \r, \t, \b, \a
+// and \xHH escapes (any other escaped byte is taken literally), single
+// quotes only \'. None on unbalanced quotes, or a closing quote not
+// followed by whitespace
+fn split_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Some(args);
+        }
+
+        let mut arg = Vec::new();
+        let mut quote = None;
+        loop {
+            let c = match (line.get(i), quote) {
+                (Some(&c), _) => c,
+                (None, None) => break,
+                (None, Some(_)) => return None,
+            };
+            match quote {
+                None if c.is_ascii_whitespace() => break,
+                None if c == b'"' || c == b'\'' => quote = Some(c),
+                None => arg.push(c),
+                Some(q) if c == q => {
+                    // closing quote must end the word
+                    if line.get(i + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                        return None;
+                    }
+                    i += 1;
+                    break;
+                }
+                Some(b'"') if c == b'\\' => {
+                    i += 1;
+                    let escaped = *line.get(i)?;
+                    arg.push(match escaped {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'b' => 8,
+                        b'a' => 7,
+                        b'x' => match line.get(i + 1..i + 3).and_then(parse_hex) {
+                            Some(byte) => {
+                                i += 2;
+                                byte
+                            }
+                            None => b'x',
+                        },
+                        other => other,
+                    });
+                }
+                Some(_) if c == b'\\' && line.get(i + 1) == Some(&b'\'') => {
+                    i += 1;
+                    arg.push(b'\'');
+                }
+                Some(_) => arg.push(c),
+            }
+            i += 1;
+        }
+        args.push(arg);
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u8> {
+    if !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    str::from_utf8(digits)
+        .ok()
+        .and_then(|s| u8::from_str_radix(s, 16).ok())
+}
+
 impl Encoder for RedisCodec {
     type Item = RedisValue;
     type Error = io::Error;
@@ -51,14 +435,543 @@ impl Encoder for RedisCodec {
     }
 }
 
-impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for RedisProto {
-    type Request = RedisValue;
-    type Response = RedisValue;
+impl<T: AsyncRead + AsyncWrite + PeerAddr + 'static> ServerProto<T> for RedisProto {
+    type Request = Request;
+    type Response = Reply;
 
-    type Transport = Framed<T, RedisCodec>;
+    type Transport = RedisTransport<T>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(RedisCodec))
+        let client = Client {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            addr: io.peer_addr(),
+        };
+        let mut transport = RedisTransport::new(io, self.limits, client);
+        transport.codec.limits = self.decode_limits;
+        Ok(transport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::redis_value::Node;
+    use super::super::commands::parse_command;
+    use super::super::store::Store;
+    use super::super::service::RedisService;
+    use super::super::monitor::{Monitors, MONITOR_BUFFER};
+    use futures::{future, task, Future};
+    use std::io::{Read, Write};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::thread;
+    use tokio_core::reactor::Core;
+    use tokio_proto::BindServer;
+
+    // a client that never reads its replies: every write would block
+    struct StalledClient;
+
+    impl Read for StalledClient {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+    impl AsyncRead for StalledClient {}
+
+    impl Write for StalledClient {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl AsyncWrite for StalledClient {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn reply() -> Reply {
+        Reply::from(RedisValue::from_slices(&[&[b'x'; 100][..]]))
+    }
+
+    #[test]
+    fn test_decode_crlf_split_across_reads() {
+        let mut codec = RedisCodec::default();
+        let mut buf = BytesMut::from(&b"+OK\r"[..]);
+
+        assert_matches!(codec.decode(&mut buf), Ok(None));
+        assert_eq!(&buf[..], b"+OK\r");
+
+        buf.extend_from_slice(b"\n");
+        assert_matches!(codec.decode(&mut buf), Ok(Some(_)));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"*1\r"[..]);
+        assert_matches!(codec.decode(&mut buf), Ok(None));
+        buf.extend_from_slice(b"\n$3\r\nGET\r");
+        assert_matches!(codec.decode(&mut buf), Ok(None));
+        buf.extend_from_slice(b"\n");
+        assert_matches!(codec.decode(&mut buf), Ok(Some(_)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_headers() {
+        let limits = DecodeLimits {
+            max_bulk_len: 16,
+            max_array_len: 2,
+            ..DecodeLimits::default()
+        };
+        let mut codec = RedisCodec { limits };
+        // rejected on the header alone, without waiting for the contents
+        let mut buf = BytesMut::from(&b"*1\r\n$17\r\n"[..]);
+        assert_matches!(codec.decode(&mut buf), Err(_));
+        let mut buf = BytesMut::from(&b"*3\r\n"[..]);
+        assert_matches!(codec.decode(&mut buf), Err(_));
+        let mut buf = BytesMut::from(&b"*2\r\n$16\r\n0123456789abcdef\r\n$0\r\n\r\n"[..]);
+        assert_matches!(codec.decode(&mut buf), Ok(Some(_)));
+
+        let mut codec = RedisCodec::default();
+        let mut buf = BytesMut::from(&b"$100000000000\r\n"[..]);
+        assert_matches!(codec.decode(&mut buf), Err(_));
+    }
+
+    #[test]
+    fn test_decode_leaves_partial_tail() {
+        let mut codec = RedisCodec::default();
+        let mut buf = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n*1\r\n$3\r\nGE"[..]);
+
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.nodes.len(), 3);
+        match frame.nodes[1] {
+            Node::Leaf(ref v) => assert_eq!(v.as_slice(), b"PING"),
+            ref node => panic!("unexpected node {:?}", node),
+        }
+        assert_eq!(&buf[..], b"*1\r\n$3\r\nGE");
+
+        assert_matches!(codec.decode(&mut buf), Ok(None));
+        assert_eq!(&buf[..], b"*1\r\n$3\r\nGE");
+
+        buf.extend_from_slice(b"T\r\n");
+        assert_matches!(codec.decode(&mut buf), Ok(Some(_)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_error_counted_in_info() {
+        let store = Store::new();
+        let protocol_errors = || {
+            let req = RedisValue::from_slices(&[b"INFO", b"stats"]);
+            let mut buf = BytesMut::new();
+            store.run_command(parse_command(req.nodes).unwrap()).encode(&mut buf);
+            let info = String::from_utf8(buf.to_vec()).unwrap();
+            let line = info.lines().find(|l| l.starts_with("protocol_errors:")).unwrap();
+            line["protocol_errors:".len()..].parse::<usize>().unwrap()
+        };
+
+        let before = protocol_errors();
+        let mut codec = RedisCodec::default();
+        let mut buf = BytesMut::from(&b"*1\r\n:abc\r\n"[..]);
+        assert_matches!(codec.decode(&mut buf), Err(_));
+        // other tests may fail decodes concurrently
+        assert!(protocol_errors() > before);
+    }
+
+    #[test]
+    fn test_output_hard_limit() {
+        let limits = OutputLimits {
+            hard: 1100,
+            ..OutputLimits::default()
+        };
+        let mut transport = RedisTransport::new(StalledClient, limits, Client::default());
+
+        // each reply is 112 bytes: *1\r\n$100\r\n...\r\n
+        for _ in 0..9 {
+            assert_matches!(transport.start_send(reply()), Ok(AsyncSink::Ready));
+            assert_matches!(transport.poll_complete(), Ok(Async::NotReady));
+        }
+        assert_matches!(transport.start_send(reply()), Err(_));
+    }
+
+    #[test]
+    fn test_output_soft_limit() {
+        let limits = OutputLimits {
+            soft: 200,
+            soft_duration: Duration::from_millis(20),
+            ..OutputLimits::default()
+        };
+        let mut transport = RedisTransport::new(StalledClient, limits, Client::default());
+
+        for _ in 0..10 {
+            assert_matches!(transport.start_send(reply()), Ok(AsyncSink::Ready));
+        }
+        assert_matches!(transport.poll_complete(), Ok(Async::NotReady));
+        thread::sleep(Duration::from_millis(20));
+        assert_matches!(transport.poll_complete(), Err(_));
+    }
+
+    #[test]
+    fn test_output_unlimited_by_default() {
+        let limits = OutputLimits::default();
+        let mut transport = RedisTransport::new(StalledClient, limits, Client::default());
+        for _ in 0..1000 {
+            assert_matches!(transport.start_send(reply()), Ok(AsyncSink::Ready));
+        }
+        assert_matches!(transport.poll_complete(), Ok(Async::NotReady));
+    }
+
+    // Both ends of an in-memory connection: the server reads what the
+    // client sends and the client reads what the server writes back.
+    // Reading with nothing sent blocks, and wakes the reader once the
+    // client sends more, or hits EOF once the client has shut down
+    #[derive(Default)]
+    struct Pipe {
+        input: Vec<u8>,
+        output: Vec<u8>,
+        // the client shut down its write half
+        input_closed: bool,
+        // the server dropped its end
+        closed: bool,
+        reader: Option<task::Task>,
+    }
+
+    // the server's end, bound through RedisProto like an accepted socket
+    struct MemoryStream(Rc<RefCell<Pipe>>);
+
+    impl MemoryStream {
+        fn new() -> (Self, Rc<RefCell<Pipe>>) {
+            let pipe = Rc::new(RefCell::new(Pipe::default()));
+            (MemoryStream(pipe.clone()), pipe)
+        }
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut pipe = self.0.borrow_mut();
+            if pipe.input.is_empty() {
+                if pipe.input_closed {
+                    return Ok(0);
+                }
+                pipe.reader = Some(task::current());
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(pipe.input.len());
+            buf[..n].copy_from_slice(&pipe.input[..n]);
+            pipe.input.drain(..n);
+            Ok(n)
+        }
+    }
+    impl AsyncRead for MemoryStream {}
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl AsyncWrite for MemoryStream {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+    impl PeerAddr for MemoryStream {
+        fn peer_addr(&self) -> Option<SocketAddr> {
+            None
+        }
+    }
+    impl Drop for MemoryStream {
+        fn drop(&mut self) {
+            self.0.borrow_mut().closed = true;
+        }
+    }
+
+    // the client's end, with the reactor serving the other one
+    struct MemoryClient {
+        pipe: Rc<RefCell<Pipe>>,
+        core: Core,
+    }
+
+    impl MemoryClient {
+        fn connect(service: RedisService) -> Self {
+            let (stream, pipe) = MemoryStream::new();
+            let core = Core::new().unwrap();
+            let proto = RedisProto::new(OutputLimits::default(), DecodeLimits::default());
+            proto.bind_server(&core.handle(), stream, service);
+            MemoryClient { pipe, core }
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            let mut pipe = self.pipe.borrow_mut();
+            pipe.input.extend_from_slice(bytes);
+            if let Some(reader) = pipe.reader.take() {
+                reader.notify();
+            }
+        }
+
+        // shuts down the write half, as a client done sending would
+        fn shutdown(&mut self) {
+            let mut pipe = self.pipe.borrow_mut();
+            pipe.input_closed = true;
+            if let Some(reader) = pipe.reader.take() {
+                reader.notify();
+            }
+        }
+
+        // runs the server until it drops the connection, returning all it
+        // wrote that hasn't been read yet
+        fn wait_closed(&mut self) -> Vec<u8> {
+            for _ in 0..100 {
+                if self.pipe.borrow().closed {
+                    return self.pipe.borrow_mut().output.split_off(0);
+                }
+                self.core.turn(Some(Duration::from_millis(10)));
+            }
+            panic!("connection not closed");
+        }
+
+        // sends request bytes and runs the server until a whole reply is
+        // back, returning it
+        fn send(&mut self, request: &[u8]) -> Vec<u8> {
+            self.write(request);
+            for _ in 0..100 {
+                let output = self.pipe.borrow().output.clone();
+                if let Ok(Some(n)) = RedisValue::frame_len(&output) {
+                    self.pipe.borrow_mut().output.drain(..n);
+                    return output[..n].to_vec();
+                }
+                self.core.turn(Some(Duration::from_millis(10)));
+            }
+            panic!("no reply to {:?}", String::from_utf8_lossy(request));
+        }
+    }
+
+    #[test]
+    fn test_in_memory_connection() {
+        let mut client = MemoryClient::connect(RedisService::new(Arc::new(Store::new())));
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+        assert_eq!(&client.send(set)[..], b"+Ok\r\n");
+        assert_eq!(&client.send(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")[..], b"$1\r\nv\r\n");
+        // a request split across reads, and an inline one
+        client.write(b"*2\r\n$3\r\nDE");
+        client.core.turn(Some(Duration::from_millis(10)));
+        assert!(client.pipe.borrow().output.is_empty());
+        assert_eq!(&client.send(b"L\r\n$1\r\nk\r\n")[..], b":1\r\n");
+        assert_eq!(&client.send(b"GET k\r\n")[..], b"$-1\r\n");
+    }
+
+    #[test]
+    fn test_protocol_error_reply() {
+        let service = || RedisService::new(Arc::new(Store::new()));
+        let mut client = MemoryClient::connect(service());
+        // requests before the bad frame are answered first, the ones after
+        // it are never read
+        client.write(b"PING\r\n*1\r\n$x\r\nPING\r\n");
+        assert_eq!(
+            &client.wait_closed()[..],
+            &b"+PONG\r\n-ERR Protocol error: invalid bulk length\r\n"[..]
+        );
+
+        let mut client = MemoryClient::connect(service());
+        client.write(b"*-5\r\n");
+        assert_eq!(
+            &client.wait_closed()[..],
+            &b"-ERR Protocol error: invalid multibulk length\r\n"[..]
+        );
+
+        let mut client = MemoryClient::connect(service());
+        client.write(b"GET \"k\r\n");
+        assert_eq!(
+            &client.wait_closed()[..],
+            &b"-ERR Protocol error: unbalanced quotes in inline command\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_multiple_frames_per_reply() {
+        let (stream, pipe) = MemoryStream::new();
+        let mut transport = RedisTransport::new(stream, OutputLimits::default(), Client::default());
+
+        let frames = vec![
+            RedisValue::from_slices(&[b"subscribe", b"a", b"1"]),
+            RedisValue::from_slices(&[b"subscribe", b"b", b"2"]),
+        ];
+        assert_matches!(transport.start_send(Reply::new(frames)), Ok(AsyncSink::Ready));
+        assert_matches!(transport.start_send(Reply::new(vec![])), Ok(AsyncSink::Ready));
+        assert_matches!(transport.poll_complete(), Ok(Async::Ready(())));
+        assert_eq!(
+            &pipe.borrow().output[..],
+            &b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n$1\r\n1\r\n\
+               *3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n$1\r\n2\r\n"[..]
+        );
+    }
+
+    fn accept() -> RedisTransport<MemoryStream> {
+        let (stream, _) = MemoryStream::new();
+        RedisProto::new(OutputLimits::default(), DecodeLimits::default())
+            .bind_transport(stream)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_client_ids() {
+        let first = accept().client.id;
+        let second = accept().client.id;
+        // other tests accept connections too, so only the order is known
+        assert!(first >= 1);
+        assert!(second > first);
+    }
+
+    #[test]
+    fn test_feed_written_as_it_arrives() {
+        let (stream, pipe) = MemoryStream::new();
+        let mut transport = RedisTransport::new(stream, OutputLimits::default(), Client::default());
+        let monitors = Monitors::new();
+
+        let reply = Reply {
+            frames: vec![RedisValue::ok()],
+            feed: Some(monitors.subscribe()),
+        };
+        assert_matches!(transport.start_send(reply), Ok(AsyncSink::Ready));
+        monitors.publish("first".to_string());
+        // polled as the dispatcher would, from within a task
+        future::poll_fn(|| transport.poll_complete()).wait().unwrap();
+        assert_eq!(&pipe.borrow().output[..], &b"+Ok\r\n+first\r\n"[..]);
+
+        monitors.publish("second".to_string());
+        future::poll_fn(|| transport.poll_complete()).wait().unwrap();
+        assert_eq!(&pipe.borrow().output[..], &b"+Ok\r\n+first\r\n+second\r\n"[..]);
+    }
+
+    #[test]
+    fn test_stalled_feed_is_dropped() {
+        let mut transport =
+            RedisTransport::new(StalledClient, OutputLimits::default(), Client::default());
+        let monitors = Monitors::new();
+        let reply = Reply {
+            frames: vec![RedisValue::ok()],
+            feed: Some(monitors.subscribe()),
+        };
+        assert_matches!(transport.start_send(reply), Ok(AsyncSink::Ready));
+
+        // nothing is taken from the feed while the reply before it is
+        // unwritten, so with no output limits the backlog is bounded by the
+        // channel, and a monitor that fills it is unsubscribed
+        let line = "x".repeat(100);
+        for _ in 0..MONITOR_BUFFER + 2 {
+            monitors.publish(line.clone());
+            let result = future::poll_fn(|| Ok::<_, ()>(Async::Ready(transport.poll_complete())))
+                .wait()
+                .unwrap();
+            assert_matches!(result, Ok(Async::NotReady));
+            assert_eq!(transport.wr.len(), 5);
+        }
+        assert!(monitors.is_empty());
+    }
+
+    #[test]
+    fn test_dropped_feed_closes_connection() {
+        let (stream, pipe) = MemoryStream::new();
+        let mut transport = RedisTransport::new(stream, OutputLimits::default(), Client::default());
+        let monitors = Monitors::new();
+        let reply = Reply {
+            frames: vec![],
+            feed: Some(monitors.subscribe()),
+        };
+        assert_matches!(transport.start_send(reply), Ok(AsyncSink::Ready));
+        for _ in 0..MONITOR_BUFFER + 2 {
+            monitors.publish("x".to_string());
+        }
+        assert!(monitors.is_empty());
+
+        // what was queued is written before the connection is dropped
+        let result = future::poll_fn(|| Ok::<_, ()>(Async::Ready(transport.poll_complete())))
+            .wait()
+            .unwrap();
+        assert_matches!(result, Err(_));
+        assert_eq!(pipe.borrow().output.len(), (MONITOR_BUFFER + 1) * b"+x\r\n".len());
+    }
+
+    #[test]
+    fn test_replies_flushed_after_client_shutdown() {
+        let mut client = MemoryClient::connect(RedisService::new(Arc::new(Store::new())));
+        client.write(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\nGET k\r\nPING\r\n");
+        client.shutdown();
+        assert_eq!(&client.wait_closed()[..], &b"+Ok\r\n$1\r\nv\r\n+PONG\r\n"[..]);
+    }
+
+    fn split(line: &str) -> Option<Vec<String>> {
+        split_args(line.as_bytes())
+            .map(|args| args.into_iter().map(|arg| String::from_utf8(arg).unwrap()).collect())
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(
+            split("SET  key \t value  "),
+            Some(vec!["SET".into(), "key".into(), "value".into()])
+        );
+        assert_eq!(split("   "), Some(vec![]));
+        assert_eq!(
+            split("SET key \"value with spaces\""),
+            Some(vec!["SET".into(), "key".into(), "value with spaces".into()])
+        );
+        assert_eq!(
+            split("'it\\'s' \"say \\\"hi\\\"\""),
+            Some(vec!["it's".into(), "say \"hi\"".into()])
+        );
+        assert_eq!(split("\"a\\tb\\x41\\xzz\\\\\""), Some(vec!["a\tbAxzz\\".into()]));
+        assert_eq!(split("'no \\n escapes'"), Some(vec!["no \\n escapes".into()]));
+        assert_eq!(split("\"\""), Some(vec!["".into()]));
+
+        assert_eq!(split("SET key \"unbalanced"), None);
+        assert_eq!(split("SET key 'unbalanced"), None);
+        assert_eq!(split("\"closed\"nospace"), None);
+    }
+
+    #[test]
+    fn test_decode_inline() {
+        let mut codec = RedisCodec::default();
+        let mut buf = BytesMut::from(&b"\r\nSET  key\t\"a b\"\r\nPING"[..]);
+
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        let args = [&b"SET"[..], b"key", b"a b"];
+        assert_eq!(frame.nodes.len(), 5);
+        for (node, arg) in frame.nodes[1..4].iter().zip(&args) {
+            match node {
+                Node::Leaf(v) => assert_eq!(v.as_slice(), *arg),
+                node => panic!("unexpected node {:?}", node),
+            }
+        }
+        assert_matches!(codec.decode(&mut buf), Ok(None));
+        buf.extend_from_slice(b"\n");
+        assert_matches!(codec.decode(&mut buf), Ok(Some(_)));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"GET \"key\r\n"[..]);
+        assert_matches!(codec.decode(&mut buf), Err(_));
+    }
+
+    #[test]
+    fn test_decode_inline_limits() {
+        let mut codec = RedisCodec::default();
+        // blank lines are skipped in a loop, however many there are
+        let mut buf = BytesMut::from(&b"\n".repeat(1_000_000)[..]);
+        buf.extend_from_slice(b"PING\r\n");
+        assert_matches!(codec.decode(&mut buf), Ok(Some(_)));
+        assert!(buf.is_empty());
+
+        // a line may be up to INLINE_MAX_SIZE long, ended or not
+        let mut buf = BytesMut::from(&vec![b'x'; INLINE_MAX_SIZE - 1][..]);
+        assert_matches!(codec.decode(&mut buf), Ok(None));
+        buf.extend_from_slice(b"\n");
+        assert_matches!(codec.decode(&mut buf), Ok(Some(_)));
+        let mut buf = BytesMut::from(&vec![b'x'; INLINE_MAX_SIZE][..]);
+        assert_matches!(codec.decode(&mut buf), Err(_));
     }
 }
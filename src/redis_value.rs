@@ -1,17 +1,42 @@
 use bytes::{BufMut, Bytes, BytesMut};
 use std::convert::{AsRef, From, Into};
+#[cfg(test)]
+use std::io::{self, BufRead};
 use std::str::{self, FromStr};
-use std::mem;
 
 type Range = ::std::ops::Range<usize>;
 
-#[derive(Debug)]
+// Bounds on the lengths a frame may declare, checked against the header
+// before any of the contents arrive, so a client can't make the reader
+// buffer (or allocate) without limit just by claiming a huge length
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeLimits {
+    pub max_bulk_len: usize,
+    pub max_array_len: usize,
+    // arrays within arrays, decoding recurses once per level
+    pub max_depth: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            // redis' proto-max-bulk-len default
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_depth: 128,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value<T> {
     SimpleString(T),
     ErrorString(T),
     IntegerString(T),
     BulkString(T),
     Nil,
+    // the null array, *-1, a reply only (LPOP with a count on a missing key)
+    NilArray,
 }
 impl<T> Value<T> {
     fn map<R, F>(self, f: F) -> Value<R>
@@ -24,16 +49,18 @@ impl<T> Value<T> {
             Value::IntegerString(x) => Value::IntegerString(f(x)),
             Value::BulkString(x) => Value::BulkString(f(x)),
             Value::Nil => Value::Nil,
+            Value::NilArray => Value::NilArray,
         }
     }
 
     pub fn as_ref(&self) -> Value<&T> {
         match self {
-            &Value::SimpleString(ref x) => Value::SimpleString(x),
-            &Value::ErrorString(ref x) => Value::ErrorString(x),
-            &Value::IntegerString(ref x) => Value::IntegerString(x),
-            &Value::BulkString(ref x) => Value::BulkString(x),
-            _ => Value::Nil,
+            Value::SimpleString(x) => Value::SimpleString(x),
+            Value::ErrorString(x) => Value::ErrorString(x),
+            Value::IntegerString(x) => Value::IntegerString(x),
+            Value::BulkString(x) => Value::BulkString(x),
+            Value::Nil => Value::Nil,
+            Value::NilArray => Value::NilArray,
         }
     }
     pub fn into_option(self) -> Option<T> {
@@ -42,13 +69,13 @@ impl<T> Value<T> {
             Value::ErrorString(x) => Some(x),
             Value::IntegerString(x) => Some(x),
             Value::BulkString(x) => Some(x),
-            Value::Nil => None,
+            Value::Nil | Value::NilArray => None,
         }
     }
     pub fn as_option(&self) -> Option<&T> {
         self.as_ref().into_option()
     }
-    pub fn iter(&self) -> ValueIter<T> {
+    pub fn iter(&self) -> ValueIter<'_, T> {
         ValueIter {
             value: self.as_ref().into_option(),
         }
@@ -65,46 +92,24 @@ impl<'a, T> Iterator for ValueIter<'a, T> {
         self.value.take()
     }
 }
-impl<T> Value<T> {
-    #[allow(dead_code)]
-    pub fn take(&mut self) -> Self {
-        mem::replace(self, Value::Nil)
-    }
-}
 impl<T: AsRef<[u8]>> Value<T> {
     pub fn as_slice(&self) -> &[u8] {
         static EMPTY: [u8; 0] = [];
         match self {
-            &Value::SimpleString(ref v) => v.as_ref(),
-            &Value::ErrorString(ref v) => v.as_ref(),
-            &Value::IntegerString(ref v) => v.as_ref(),
-            &Value::BulkString(ref v) => v.as_ref(),
-            &Value::Nil => &EMPTY,
-        }
-    }
-}
-impl Value<Bytes> {
-    pub fn from_slice(s: &[u8]) -> Self {
-        if s.len() > 0 {
-            Value::BulkString(Bytes::from(s))
-        } else {
-            Value::Nil
+            Value::SimpleString(v) => v.as_ref(),
+            Value::ErrorString(v) => v.as_ref(),
+            Value::IntegerString(v) => v.as_ref(),
+            Value::BulkString(v) => v.as_ref(),
+            Value::Nil | Value::NilArray => &EMPTY,
         }
     }
-    pub fn from_integer<N: Into<i64>>(n: N) -> Self {
-        let n = n.into();
-        let number = format!("{}", n).into_bytes();
-        Value::IntegerString(Bytes::from(number))
-    }
-    pub fn from_error(e: &str) -> Self {
-        Value::ErrorString(Bytes::from(e))
-    }
     pub fn size(&self) -> usize {
-        let content_len = self.as_option().map_or(0, |b| b.len());
-        match self {
-            &Value::Nil => "$-1\r\n".len(),
-            // $ (1 byte) + usize_size(content_len) + \r\n (2) + contents + \r\n (2)
-            &Value::BulkString(_) => 1 + usize_string_size(content_len) + 2 + content_len + 2,
+        let content_len = self.as_option().map_or(0, |b| b.as_ref().len());
+        match *self {
+            Value::Nil => "$-1\r\n".len(),
+            Value::NilArray => "*-1\r\n".len(),
+            // $ (1 byte) + int_size(content_len) + \r\n (2) + contents + \r\n (2)
+            Value::BulkString(_) => 1 + int_string_size(content_len as i64) + 2 + content_len + 2,
             // + (1 byte) + content_len + \r\n (2 bytes)
             _ => 1 + content_len + 2,
         }
@@ -112,43 +117,110 @@ impl Value<Bytes> {
     // may panic assume buf has enough capacity (thus should not make this fn public)
     fn encode(&self, buf: &mut BytesMut) {
         match self {
-            &Value::Nil => {
+            Value::Nil => {
                 buf.put("$-1\r\n");
             }
-            &Value::SimpleString(ref b) => {
+            Value::NilArray => {
+                buf.put("*-1\r\n");
+            }
+            Value::SimpleString(b) => {
                 buf.put(b'+');
-                buf.extend_from_slice(&b[..]);
-                buf.put("\r\n");
+                put_line(b.as_ref(), buf);
             }
-            &Value::ErrorString(ref b) => {
+            Value::ErrorString(b) => {
                 buf.put(b'-');
-                buf.extend_from_slice(&b[..]);
-                buf.put("\r\n");
+                put_line(b.as_ref(), buf);
             }
-            &Value::IntegerString(ref b) => {
+            Value::IntegerString(b) => {
                 buf.put(b':');
-                buf.extend_from_slice(&b[..]);
+                buf.extend_from_slice(b.as_ref());
                 buf.put("\r\n");
             }
-            &Value::BulkString(ref b) => {
+            Value::BulkString(b) => {
                 buf.put(b'$');
-                buf.put(format!("{}", b.len()));
+                buf.put(format!("{}", b.as_ref().len()));
                 buf.put("\r\n");
-                buf.extend_from_slice(&b[..]);
+                buf.extend_from_slice(b.as_ref());
                 buf.put("\r\n");
             }
         }
     }
 }
+impl Value<Bytes> {
+    pub fn from_slice(s: &[u8]) -> Self {
+        if !s.is_empty() {
+            Value::BulkString(Bytes::from(s))
+        } else {
+            Value::Nil
+        }
+    }
+    pub fn from_integer<N: Into<i64>>(n: N) -> Self {
+        let n = n.into();
+        let number = format!("{}", n).into_bytes();
+        Value::IntegerString(Bytes::from(number))
+    }
+    pub fn from_error(e: &str) -> Self {
+        Value::ErrorString(Bytes::from(e))
+    }
+    pub fn from_status(s: &str) -> Self {
+        Value::SimpleString(Bytes::from(s))
+    }
+}
+// Status and error replies end at the first CRLF, so a CR or LF inside
+// one would cut it short and leave the client reading the rest as the next
+// reply. Like redis, these are written as spaces, keeping size() exact
+fn put_line(line: &[u8], buf: &mut BytesMut) {
+    if line.iter().any(|&c| c == b'\r' || c == b'\n') {
+        buf.extend(line.iter().map(|&c| if c == b'\r' || c == b'\n' { b' ' } else { c }));
+    } else {
+        buf.extend_from_slice(line);
+    }
+    buf.put("\r\n");
+}
 
-#[derive(Debug)]
+// Conversions for building replies: integers and booleans (0/1) are
+// integer replies, &str a status reply, byte buffers bulk strings (an
+// empty one stays an empty bulk, unlike from_slice) and None nil
+impl From<i64> for Value<Bytes> {
+    fn from(n: i64) -> Self {
+        Value::from_integer(n)
+    }
+}
+impl From<bool> for Value<Bytes> {
+    fn from(b: bool) -> Self {
+        Value::from_integer(b as i64)
+    }
+}
+impl<'a> From<&'a str> for Value<Bytes> {
+    fn from(s: &'a str) -> Self {
+        Value::from_status(s)
+    }
+}
+impl From<Vec<u8>> for Value<Bytes> {
+    fn from(v: Vec<u8>) -> Self {
+        Value::BulkString(Bytes::from(v))
+    }
+}
+impl From<Bytes> for Value<Bytes> {
+    fn from(b: Bytes) -> Self {
+        Value::BulkString(b)
+    }
+}
+impl<T: Into<Value<Bytes>>> From<Option<T>> for Value<Bytes> {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Value::Nil, Into::into)
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Node<T> {
     Leaf(Value<T>),
     Open(usize),
     Close,
+    // a part of a reply that is already encoded (RedisValue::streamed_array)
+    Encoded(Bytes),
 }
 impl<T> Node<T> {
-    #[allow(dead_code)]
     fn map<R, F>(self, f: F) -> Node<R>
     where
         F: FnOnce(T) -> R,
@@ -157,17 +229,18 @@ impl<T> Node<T> {
             Node::Leaf(v) => Node::Leaf(v.map(f)),
             Node::Open(n) => Node::Open(n),
             Node::Close => Node::Close,
+            Node::Encoded(b) => Node::Encoded(b),
         }
     }
-    fn value_iter(&self) -> ValueIter<T> {
+    fn value_iter(&self) -> ValueIter<'_, T> {
         match self {
-            &Node::Leaf(ref x) => x.iter(),
+            Node::Leaf(x) => x.iter(),
             _ => ValueIter { value: None },
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct RedisValue {
     pub nodes: Vec<Node<Bytes>>,
 }
@@ -182,24 +255,91 @@ impl RedisValue {
             nodes: vec![Node::Leaf(v)],
         }
     }
+    // an array of (possibly nested) items
+    pub fn array(items: Vec<RedisValue>) -> Self {
+        let mut nodes = vec![Node::Open(items.len())];
+        for item in items {
+            nodes.extend(item.nodes);
+        }
+        nodes.push(Node::Close);
+        RedisValue { nodes }
+    }
+    // a flat array of values
+    pub fn from_values(values: Vec<Value<Bytes>>) -> Self {
+        let mut nodes = Vec::with_capacity(values.len() + 2);
+        nodes.push(Node::Open(values.len()));
+        nodes.extend(values.into_iter().map(Node::Leaf));
+        nodes.push(Node::Close);
+        RedisValue { nodes }
+    }
+    // A request (array of bulk strings) built from raw arguments, the
+    // arguments are copied into one shared buffer like decode does
+    pub fn from_slices(args: &[&[u8]]) -> Self {
+        let n_bytes = args.iter().map(|arg| arg.len()).sum();
+        let mut bytes = Bytes::with_capacity(n_bytes);
+        for arg in args {
+            bytes.extend_from_slice(arg);
+        }
+
+        let mut nodes = Vec::with_capacity(args.len() + 2);
+        nodes.push(Node::Open(args.len()));
+        let mut start = 0;
+        for arg in args {
+            let end = start + arg.len();
+            nodes.push(Node::Leaf(Value::BulkString(bytes.slice(start, end))));
+            start = end;
+        }
+        nodes.push(Node::Close);
+        RedisValue { nodes }
+    }
     pub fn size(&self) -> usize {
         self.nodes
             .iter()
             .map(|node| {
                 match node {
-                    &Node::Open(n) => usize_string_size(n) + 3, // '*' + <n item> | \r\n
-                    &Node::Close => 0,
-                    &Node::Leaf(ref v) => v.size(),
+                    Node::Open(n) => int_string_size(*n as i64) + 3, // '*' + <n item> | \r\n
+                    Node::Close => 0,
+                    Node::Leaf(v) => v.size(),
+                    Node::Encoded(b) => b.len(),
                 }
             })
             .sum()
     }
-    pub fn decode<B: AsRef<[u8]>>(buf: &B) -> Result<Option<(usize, Self)>, ()> {
+    // Byte length of the complete frame at the start of buf, None if more
+    // bytes are needed; validates framing without copying any contents
+    #[cfg(test)]
+    pub fn frame_len<B: AsRef<[u8]>>(buf: &B) -> Result<Option<usize>, ()> {
+        match decode_values_from_slice(buf.as_ref(), &DecodeLimits::default(), 0) {
+            Ok((consumed, _)) => Ok(Some(consumed)),
+            Err(DecodeError::Incomplete) => Ok(None),
+            Err(DecodeError::Failed(_)) => Err(()),
+        }
+    }
+    // Decodes buf as exactly one complete frame, for one-shot parsing: an
+    // incomplete frame or bytes left over after it are errors
+    #[cfg(test)]
+    pub fn decode_exact<B: AsRef<[u8]>>(buf: &B) -> Result<Self, ()> {
+        match RedisValue::decode(buf).map_err(|_| ())? {
+            Some((consumed, value)) if consumed == buf.as_ref().len() => Ok(value),
+            _ => Err(()),
+        }
+    }
+    #[cfg(test)]
+    pub fn decode<B: AsRef<[u8]>>(buf: &B) -> Result<Option<(usize, Self)>, &'static str> {
+        RedisValue::decode_with_limits(buf, &DecodeLimits::default())
+    }
+    // Decodes the frame at the start of buf, None if more bytes are needed.
+    // A malformed frame fails with the reason, worded like redis' protocol
+    // errors
+    pub fn decode_with_limits<B: AsRef<[u8]>>(
+        buf: &B,
+        limits: &DecodeLimits,
+    ) -> Result<Option<(usize, Self)>, &'static str> {
         let buf = buf.as_ref();
-        let result = decode_values_from_slice(buf);
+        let result = decode_values_from_slice(buf, limits, 0);
         match result {
             Err(DecodeError::Incomplete) => Ok(None),
-            Err(DecodeError::Failed) => Err(()),
+            Err(DecodeError::Failed(reason)) => Err(reason),
             Ok((consumed, values)) => {
                 let n_bytes = values.byte_count();
                 match values {
@@ -238,6 +378,7 @@ impl RedisValue {
                                 }
                                 Node::Open(count) => Node::Open(count),
                                 Node::Close => Node::Close,
+                                Node::Encoded(b) => Node::Encoded(b),
                             })
                             .collect();
                         Ok(Some((consumed, RedisValue { nodes })))
@@ -251,18 +392,146 @@ impl RedisValue {
         buf.reserve(self.size());
         for node in &self.nodes {
             match node {
-                &Node::Open(n) => {
+                Node::Open(n) => {
                     buf.put(b'*');
                     buf.put(format!("{}", n));
                     buf.put("\r\n");
                 }
-                &Node::Leaf(ref v) => {
+                Node::Leaf(v) => {
                     v.encode(buf);
                 }
-                _ => {}
+                Node::Encoded(b) => buf.extend_from_slice(b),
+                Node::Close => {}
             }
         }
     }
+    // A flat array of the n items, encoded as they come: for large replies
+    // (LRANGE, HGETALL) the elements are written straight into one buffer
+    // rather than each copied into a Value and a node of their own first
+    pub fn streamed_array<T, I>(n: usize, items: I) -> Self
+    where
+        T: AsRef<[u8]>,
+        I: IntoIterator<Item = Value<T>>,
+    {
+        let mut buf = BytesMut::new();
+        RedisValue::encode_array(n, items, &mut buf);
+        RedisValue {
+            nodes: vec![Node::Encoded(buf.freeze())],
+        }
+    }
+    // Writes the *n header, then each of the n items; buffer space is
+    // reserved one element at a time
+    pub fn encode_array<T, I>(n: usize, items: I, buf: &mut BytesMut)
+    where
+        T: AsRef<[u8]>,
+        I: IntoIterator<Item = Value<T>>,
+    {
+        buf.reserve(int_string_size(n as i64) + 3);
+        buf.put(b'*');
+        buf.put(format!("{}", n));
+        buf.put("\r\n");
+
+        let mut encoded = 0;
+        for item in items {
+            buf.reserve(item.size());
+            item.encode(buf);
+            encoded += 1;
+        }
+        // a wrong count would leave the client misreading every reply after
+        assert_eq!(encoded, n, "array of {} items given {}", n, encoded);
+    }
+}
+
+// Reads one complete reply from a blocking reader, for tests; reads no
+// further than the end of that reply, so it can be called again for the
+// next one. Unlike RedisValue::decode, *-1 reads as the null array. Lengths
+// over the default DecodeLimits are rejected before allocating
+#[cfg(test)]
+pub fn read_value<R: BufRead>(r: &mut R) -> io::Result<RedisValue> {
+    let mut nodes = Vec::new();
+    read_nodes(r, &mut nodes, &DecodeLimits::default(), 0)?;
+    Ok(RedisValue { nodes })
+}
+
+#[cfg(test)]
+fn read_nodes<R: BufRead>(
+    r: &mut R,
+    nodes: &mut Vec<Node<Bytes>>,
+    limits: &DecodeLimits,
+    depth: usize,
+) -> io::Result<()> {
+    let mut line = Vec::new();
+    r.read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(match line.last() {
+            Some(b'\n') => io_error!(InvalidData, "line not ended by CRLF"),
+            _ => io_error!(UnexpectedEof, "reply ended early"),
+        });
+    }
+    // a type byte, then CRLF
+    if line.len() < 3 {
+        return Err(io_error!(InvalidData, "empty line"));
+    }
+    let content = &line[1..line.len() - 2];
+    let len = || parse_int(content).ok_or_else(|| io_error!(InvalidData, "invalid length"));
+    match line[0] {
+        b'+' => nodes.push(Node::Leaf(Value::SimpleString(Bytes::from(content)))),
+        b'-' => nodes.push(Node::Leaf(Value::ErrorString(Bytes::from(content)))),
+        b':' => {
+            if parse_int(content).is_none() {
+                return Err(io_error!(InvalidData, "invalid integer"));
+            }
+            nodes.push(Node::Leaf(Value::IntegerString(Bytes::from(content))));
+        }
+        b'$' => match len()? {
+            -1 => nodes.push(Node::Leaf(Value::Nil)),
+            n if n < 0 || n as usize > limits.max_bulk_len => {
+                return Err(io_error!(InvalidData, "invalid length"))
+            }
+            n => {
+                let mut bulk = vec![0; n as usize + 2];
+                r.read_exact(&mut bulk)?;
+                if !bulk.ends_with(b"\r\n") {
+                    return Err(io_error!(InvalidData, "bulk string not ended by CRLF"));
+                }
+                bulk.truncate(n as usize);
+                nodes.push(Node::Leaf(Value::BulkString(Bytes::from(bulk))));
+            }
+        },
+        b'*' => match len()? {
+            -1 => nodes.push(Node::Leaf(Value::NilArray)),
+            n if n < 0 || n as usize > limits.max_array_len => {
+                return Err(io_error!(InvalidData, "invalid length"))
+            }
+            _ if depth >= limits.max_depth => {
+                return Err(io_error!(InvalidData, "arrays nested too deep"))
+            }
+            n => {
+                nodes.push(Node::Open(n as usize));
+                for _ in 0..n {
+                    read_nodes(r, nodes, limits, depth + 1)?;
+                }
+                nodes.push(Node::Close);
+            }
+        },
+        _ => return Err(io_error!(InvalidData, "unknown reply type")),
+    }
+    Ok(())
+}
+
+// Test helper pinning the exact bytes a reply is written as, shown as text
+// on failure
+#[cfg(test)]
+#[track_caller]
+pub fn assert_encodes_as(value: &RedisValue, expected: &[u8]) {
+    let mut buf = BytesMut::new();
+    value.encode(&mut buf);
+    assert_eq!(
+        String::from_utf8_lossy(&buf),
+        String::from_utf8_lossy(expected),
+        "{:?}",
+        value
+    );
 }
 
 #[derive(Debug)]
@@ -273,8 +542,8 @@ enum Values {
 impl Values {
     fn byte_count(&self) -> usize {
         match self {
-            &Values::One(ref v) => v.iter().map(|r| r.end - r.start).sum(),
-            &Values::Many(ref nodes) => nodes
+            Values::One(v) => v.iter().map(|r| r.end - r.start).sum(),
+            Values::Many(nodes) => nodes
                 .iter()
                 .flat_map(Node::value_iter)
                 .map(|r| r.end - r.start)
@@ -284,7 +553,8 @@ impl Values {
 }
 #[derive(Debug)]
 enum DecodeError {
-    Failed,
+    // why the frame is malformed
+    Failed(&'static str),
     Incomplete,
 }
 
@@ -292,43 +562,66 @@ enum DecodeError {
 type Decoded = (usize, Values);
 type DecodeResult = ::std::result::Result<Decoded, DecodeError>;
 
-fn decode_values_from_slice(src: &[u8]) -> DecodeResult {
+// depth is the number of arrays enclosing src
+fn decode_values_from_slice(src: &[u8], limits: &DecodeLimits, depth: usize) -> DecodeResult {
     let len = src.len();
 
-    if len < 4 {
+    if len < 3 {
         // needs at least prefix + '\r\n'
         // prefix = + | - | : | $ | *
         return Err(DecodeError::Incomplete);
     }
     // find \n position
     if let Some(n) = src.iter().position(|b| *b == b'\n') {
-        // requires CLRF ending
-        if n <= 2 || src[n - 1] != b'\r' {
-            return Err(DecodeError::Failed);
+        // requires CLRF ending; only status and error lines may be empty
+        // ("+\r\n"), the others fail to parse their empty number
+        if n < 2 || src[n - 1] != b'\r' {
+            return Err(DecodeError::Failed("line not ended by CRLF"));
         }
         match src[0] {
             b'*' => {
-                let array_len = str::from_utf8(&src[1..n - 1]).map_err(|_| DecodeError::Failed)?;
-                let array_len = usize::from_str(array_len).map_err(|_| DecodeError::Failed)?;
+                let array_len = str::from_utf8(&src[1..n - 1]).ok();
+                let array_len = array_len.and_then(|len| usize::from_str(len).ok());
+                let array_len = array_len.ok_or(DecodeError::Failed("invalid multibulk length"))?;
+                if array_len > limits.max_array_len {
+                    return Err(DecodeError::Failed("invalid multibulk length"));
+                }
+                if depth >= limits.max_depth {
+                    return Err(DecodeError::Failed("arrays nested too deep"));
+                }
 
-                if len > n + 1 {
+                // an empty array is complete after its header
+                if array_len == 0 || len > n + 1 {
                     // more bytes avaiable
                     let mut nodes: Vec<Node<Range>> = Vec::with_capacity(32);
                     let mut index: usize = n + 1;
                     nodes.push(Node::Open(array_len));
                     for _ in 0..array_len {
                         // decode one
-                        let (consumed, result) = decode_values_from_slice(&src[index..])?;
+                        let element = decode_values_from_slice(&src[index..], limits, depth + 1);
+                        let (consumed, result) = match element {
+                            Ok(decoded) => decoded,
+                            // the array header is fine, but this element (or a later
+                            // one) is still in flight: wait for more bytes rather than
+                            // rejecting the whole frame
+                            Err(DecodeError::Incomplete) => return Err(DecodeError::Incomplete),
+                            // a malformed element poisons the whole array
+                            Err(failed @ DecodeError::Failed(_)) => return Err(failed),
+                        };
                         match result {
                             Values::One(value) => {
-                                let value = value.map(|rng| (rng.start + index..rng.end + index));
+                                let value = value.map(|rng| rng.start + index..rng.end + index);
                                 nodes.push(Node::Leaf(value));
                             }
-                            Values::Many(mut inner_nodes) => for n in inner_nodes.drain(..) {
-                                nodes.push(n);
-                            },
+                            // a nested array's ranges are relative to where it
+                            // starts, like a single value's
+                            Values::Many(mut inner_nodes) => nodes.extend(
+                                inner_nodes
+                                    .drain(..)
+                                    .map(|node| node.map(|rng| rng.start + index..rng.end + index)),
+                            ),
                         }
-                        index = index + consumed;
+                        index += consumed;
                     }
                     nodes.push(Node::Close);
                     Ok((index, Values::Many(nodes)))
@@ -337,26 +630,27 @@ fn decode_values_from_slice(src: &[u8]) -> DecodeResult {
                     Err(DecodeError::Incomplete)
                 }
             }
-            _ => decode_one(src),
+            _ => decode_one(src, limits),
         }
     } else {
         Err(DecodeError::Incomplete)
     }
 }
 
-fn decode_one(src: &[u8]) -> DecodeResult {
+fn decode_one(src: &[u8], limits: &DecodeLimits) -> DecodeResult {
     let len = src.len();
 
-    if len < 4 {
+    if len < 3 {
         // needs at least prefix + '\r\n'
         // prefix = + | - | : | $ | *
         return Err(DecodeError::Incomplete);
     }
     // find \n position
     if let Some(n) = src.iter().position(|b| *b == b'\n') {
-        // requires CLRF ending
-        if n <= 2 || src[n - 1] != b'\r' {
-            return Err(DecodeError::Failed);
+        // requires CLRF ending; only status and error lines may be empty
+        // ("+\r\n"), the others fail to parse their empty number
+        if n < 2 || src[n - 1] != b'\r' {
+            return Err(DecodeError::Failed("line not ended by CRLF"));
         }
         match src[0] {
             b'+' => {
@@ -368,26 +662,31 @@ fn decode_one(src: &[u8]) -> DecodeResult {
                 Ok((n + 1, Values::One(value)))
             }
             b':' => {
+                // don't let arbitrary bytes masquerade as an integer
+                if parse_int(&src[1..n - 1]).is_none() {
+                    return Err(DecodeError::Failed("invalid integer"));
+                }
                 let value = Value::IntegerString(1..n - 1);
                 Ok((n + 1, Values::One(value)))
             }
             b'$' => {
-                let bulk_len = str::from_utf8(&src[1..n - 1]).map_err(|_| DecodeError::Failed)?;
-                let bulk_len = isize::from_str(bulk_len).map_err(|_| DecodeError::Failed)?;
+                let bulk_len = str::from_utf8(&src[1..n - 1]).ok();
+                let bulk_len = bulk_len.and_then(|len| isize::from_str(len).ok());
+                let bulk_len = bulk_len.ok_or(DecodeError::Failed("invalid bulk length"))?;
 
                 // Nil
                 if bulk_len == -1 {
                     Ok((n + 1, Values::One(Value::Nil)))
-                // Negative length other than -1
-                } else if bulk_len < 0 {
-                    Err(DecodeError::Failed)
+                // Negative length other than -1, or over the limit
+                } else if bulk_len < 0 || bulk_len as usize > limits.max_bulk_len {
+                    Err(DecodeError::Failed("invalid bulk length"))
                 } else {
                     let bulk_len = bulk_len as usize;
                     // prefix(n+1) + bulk_len + 2 bytes CRLF
                     if len >= bulk_len + n + 3 {
                         let ending = &src[n + bulk_len + 1..n + bulk_len + 3];
                         if ending != b"\r\n" {
-                            Err(DecodeError::Failed)
+                            Err(DecodeError::Failed("bulk string not ended by CRLF"))
                         } else {
                             let value = Value::BulkString(n + 1..n + bulk_len + 1);
                             // n + 1 prefix, bulk_len bytes, 2 bytes line ending
@@ -399,16 +698,26 @@ fn decode_one(src: &[u8]) -> DecodeResult {
                     }
                 }
             }
-            b'*' => decode_values_from_slice(&src[n + 1..]),
-            _ => Err(DecodeError::Failed),
+            // arrays are decoded by decode_values_from_slice, which only
+            // hands other frames to decode_one
+            _ => Err(DecodeError::Failed("unknown frame type")),
         }
     } else {
         Err(DecodeError::Incomplete)
     }
 }
 
-fn usize_string_size(mut v: usize) -> usize {
-    let mut result = 1;
+// parses a decimal integer, None if s is not valid utf8, not a number or
+// out of i64 range
+pub fn parse_int(s: &[u8]) -> Option<i64> {
+    str::from_utf8(s).ok().and_then(|s| i64::from_str(s).ok())
+}
+
+// number of bytes in the decimal representation of n (what format!("{}", n)
+// would produce), including the leading '-' for negative numbers
+fn int_string_size(n: i64) -> usize {
+    let mut v = n.unsigned_abs();
+    let mut result = if n < 0 { 2 } else { 1 };
     loop {
         if v < 10 {
             return result;
@@ -432,6 +741,33 @@ fn usize_string_size(mut v: usize) -> usize {
 mod tests {
     use super::*;
 
+    // the decoder under the default limits
+    fn decode_values_from_slice(src: &[u8]) -> DecodeResult {
+        super::decode_values_from_slice(src, &DecodeLimits::default(), 0)
+    }
+
+    #[test]
+    fn test_value_from() {
+        assert_matches!(Value::from(-12), Value::IntegerString(ref b) if b == "-12");
+        assert_matches!(Value::from(true), Value::IntegerString(ref b) if b == "1");
+        assert_matches!(Value::from(false), Value::IntegerString(ref b) if b == "0");
+        assert_matches!(Value::from("PONG"), Value::SimpleString(ref b) if b == "PONG");
+        assert_matches!(Value::from(b"foo".to_vec()), Value::BulkString(ref b) if b == "foo");
+        assert_matches!(Value::from(Vec::new()), Value::BulkString(ref b) if b.is_empty());
+        assert_matches!(Value::from(Bytes::from("foo")), Value::BulkString(ref b) if b == "foo");
+        assert_matches!(Value::from(Some(b"foo".to_vec())), Value::BulkString(ref b) if b == "foo");
+        assert_matches!(Value::from(Some(3)), Value::IntegerString(ref b) if b == "3");
+        assert_matches!(Value::from(None::<Vec<u8>>), Value::Nil);
+    }
+
+    #[test]
+    fn test_from_status() {
+        let pong = Value::from_status("PONG");
+        assert_eq!(pong.size(), 7);
+        assert_encodes_as(&RedisValue::from_value(pong), b"+PONG\r\n");
+        assert_encodes_as(&RedisValue::from_value(Value::from_status("")), b"+\r\n");
+    }
+
     #[test]
     fn test_decode_ok() {
         let ok_tests: Vec<&str> = vec![
@@ -446,10 +782,114 @@ mod tests {
         for raw in &ok_tests {
             let result = decode_values_from_slice(raw.as_bytes());
             let (consumed, _) = result.unwrap();
-            assert_eq!(consumed, raw.as_bytes().len());
+            assert_eq!(consumed, raw.len());
         }
     }
 
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        // xorshift, so every run checks the same trees
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        fn leaf(next: &mut dyn FnMut(u64) -> u64) -> Value<Bytes> {
+            let text = |next: &mut dyn FnMut(u64) -> u64| {
+                let len = next(6);
+                (0..len).map(|_| b"abc xyz"[next(7) as usize]).collect::<Vec<u8>>()
+            };
+            match next(5) {
+                0 => Value::SimpleString(Bytes::from(text(next))),
+                1 => Value::ErrorString(Bytes::from(text(next))),
+                2 => Value::from_integer(next(u64::MAX) as i64),
+                3 => {
+                    // any bytes at all, CRLF included
+                    let len = next(20);
+                    Value::BulkString((0..len).map(|_| next(256) as u8).collect::<Vec<u8>>().into())
+                }
+                _ => Value::Nil,
+            }
+        }
+        fn tree(next: &mut dyn FnMut(u64) -> u64, depth: u32, nodes: &mut Vec<Node<Bytes>>) {
+            if depth == 0 || next(3) == 0 {
+                nodes.push(Node::Leaf(leaf(next)));
+                return;
+            }
+            let len = next(5) as usize;
+            nodes.push(Node::Open(len));
+            for _ in 0..len {
+                tree(next, depth - 1, nodes);
+            }
+            nodes.push(Node::Close);
+        }
+
+        let explicit = vec![
+            vec![Node::Open(0), Node::Close],
+            vec![Node::Leaf(Value::Nil)],
+            vec![Node::Leaf(Value::SimpleString(Bytes::new()))],
+            vec![Node::Open(1), Node::Open(0), Node::Close, Node::Close],
+            vec![
+                Node::Open(2),
+                Node::Open(2),
+                Node::Leaf(Value::BulkString(Bytes::from("a"))),
+                Node::Leaf(Value::Nil),
+                Node::Close,
+                Node::Open(0),
+                Node::Close,
+                Node::Close,
+            ],
+        ];
+        let random = (0..500).map(|_| {
+            let mut nodes = Vec::new();
+            tree(&mut next, 4, &mut nodes);
+            nodes
+        });
+        for nodes in explicit.into_iter().chain(random) {
+            let value = RedisValue { nodes };
+            let mut buf = BytesMut::new();
+            value.encode(&mut buf);
+            assert_eq!(RedisValue::decode(&buf), Ok(Some((buf.len(), value))));
+        }
+    }
+
+    #[test]
+    fn test_decode_nested_array_offsets() {
+        let raw = "*1\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n";
+        assert_matches!(decode_values_from_slice(raw.as_bytes()), Ok((n, _)) if n == raw.len());
+        let (consumed, value) = RedisValue::decode(&raw).unwrap().unwrap();
+        assert_eq!(consumed, raw.len());
+        assert_eq!(
+            value.nodes,
+            vec![
+                Node::Open(1),
+                Node::Open(2),
+                Node::Leaf(Value::BulkString(Bytes::from("a"))),
+                Node::Leaf(Value::BulkString(Bytes::from("b"))),
+                Node::Close,
+                Node::Close,
+            ]
+        );
+        // followed by more, the nested array's length still counts once
+        let (consumed, _) = RedisValue::decode(&format!("{}+next\r\n", raw)).unwrap().unwrap();
+        assert_eq!(consumed, raw.len());
+    }
+
+    #[test]
+    fn test_decode_exact() {
+        assert_eq!(
+            RedisValue::decode_exact(&"$3\r\nfoo\r\n"),
+            Ok(RedisValue::from_value(Value::BulkString(Bytes::from("foo"))))
+        );
+        assert!(RedisValue::decode_exact(&"$3\r\nfoo\r\nEXTRA").is_err());
+        assert!(RedisValue::decode_exact(&"+OK\r\n+OK\r\n").is_err());
+        assert!(RedisValue::decode_exact(&"$3\r\nfo").is_err());
+        assert!(RedisValue::decode_exact(&"").is_err());
+        assert!(RedisValue::decode_exact(&"*0\r\n").is_ok());
+    }
+
     #[test]
     fn test_decode_incomplete() {
         let incomplete_tests: Vec<&str> = vec![
@@ -460,6 +900,9 @@ mod tests {
             "$8\r\nfoo\r",
             "$-1\r",
             "*2\r\n$3\r\nfoo\r\n",
+            "*2\r\n$3\r\nfoo\r\n$3\r\nba",
+            "*2\r\n$3\r\nfoo\r\n$3\r",
+            "*2\r\n*1\r\n:1\r\n*1\r\n",
         ];
         for raw in &incomplete_tests {
             let result = decode_values_from_slice(raw.as_bytes());
@@ -467,6 +910,157 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decode_bulk_terminator_bounds() {
+        // the terminator check reads n + bulk_len + 1..n + bulk_len + 3,
+        // only once the length check has put that whole range in bounds
+        assert_matches!(decode_values_from_slice(b"$3\r\nfoo\r\n"), Ok((9, _)));
+        assert_matches!(decode_values_from_slice(b"$0\r\n\r\n"), Ok((6, _)));
+        assert_matches!(decode_values_from_slice(b"$3\r\nfoo\r"), Err(DecodeError::Incomplete));
+        assert_matches!(decode_values_from_slice(b"$3\r\nfoo"), Err(DecodeError::Incomplete));
+        assert_matches!(decode_values_from_slice(b"$3\r\nfoo\n\r"), Err(DecodeError::Failed(_)));
+        assert_matches!(decode_values_from_slice(b"$3\r\nfooo\n"), Err(DecodeError::Failed(_)));
+
+        // every strict prefix of a frame is incomplete, never a panic
+        let frame = b"*3\r\n$3\r\nfoo\r\n$0\r\n\r\n*1\r\n$2\r\nab\r\n";
+        for end in 0..frame.len() {
+            assert_matches!(decode_values_from_slice(&frame[..end]), Err(DecodeError::Incomplete));
+        }
+        assert_matches!(decode_values_from_slice(frame), Ok((n, _)) if n == frame.len());
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let limits = DecodeLimits {
+            max_bulk_len: 3,
+            max_array_len: 1,
+            ..DecodeLimits::default()
+        };
+        let decode = |raw: &str| RedisValue::decode_with_limits(&raw, &limits);
+        assert_matches!(decode("$3\r\nfoo\r\n"), Ok(Some(_)));
+        assert_matches!(decode("*1\r\n$3\r\nfoo\r\n"), Ok(Some(_)));
+        assert_matches!(decode("$4\r\n"), Err("invalid bulk length"));
+        assert_matches!(decode("*2\r\n"), Err("invalid multibulk length"));
+        assert_matches!(decode("*1\r\n*2\r\n"), Err("invalid multibulk length"));
+        assert_matches!(decode("*1\r\n$4\r\nfoo"), Err("invalid bulk length"));
+
+        // the defaults still refuse a length no client should send
+        assert!(RedisValue::decode(&"$100000000000\r\n").is_err());
+        assert!(RedisValue::decode(&"*100000000000\r\n").is_err());
+        assert!(read_value(&mut &b"$100000000000\r\n"[..]).is_err());
+        assert!(read_value(&mut &b"*100000000000\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_decode_max_depth() {
+        let nested = |depth: usize| "*1\r\n".repeat(depth) + ":1\r\n";
+        let limits = DecodeLimits::default();
+
+        let at_limit = nested(limits.max_depth);
+        assert_matches!(RedisValue::decode(&at_limit), Ok(Some((n, _))) if n == at_limit.len());
+        assert!(read_value(&mut at_limit.as_bytes()).is_ok());
+        assert!(RedisValue::decode(&nested(limits.max_depth + 1)).is_err());
+        assert!(read_value(&mut nested(limits.max_depth + 1).as_bytes()).is_err());
+        // fails as soon as the limit is passed, complete or not, and deep
+        // enough to overflow the stack if decoding recursed all the way
+        let deep = "*1\r\n".repeat(1_000_000);
+        assert!(RedisValue::decode(&deep).is_err());
+        assert!(read_value(&mut deep.as_bytes()).is_err());
+
+        let shallow = DecodeLimits {
+            max_depth: 1,
+            ..limits
+        };
+        assert_matches!(RedisValue::decode_with_limits(&"*1\r\n:1\r\n", &shallow), Ok(Some(_)));
+        assert!(RedisValue::decode_with_limits(&"*1\r\n*0\r\n", &shallow).is_err());
+    }
+
+    #[test]
+    fn test_decode_invalid_integer() {
+        let failed_tests: Vec<&str> = vec![":xyz\r\n", ":12a\r\n", ":1.5\r\n", "*1\r\n:xyz\r\n"];
+        for raw in &failed_tests {
+            let result = decode_values_from_slice(raw.as_bytes());
+            assert_matches!(result, Err(DecodeError::Failed(_)));
+        }
+        assert!(RedisValue::decode(&":xyz\r\n").is_err());
+        assert_matches!(decode_values_from_slice(b":-12\r\n"), Ok((6, _)));
+    }
+
+    #[test]
+    fn test_decode_integer_range() {
+        // integer replies must fit in an i64: anything decoded as one can be
+        // parsed back, and an overflowing line fails like any other bad one
+        for n in &[i64::MIN, -1, 0, i64::MAX] {
+            let mut buf = BytesMut::new();
+            RedisValue::from_value(Value::from_integer(*n)).encode(&mut buf);
+            let (_, value) = RedisValue::decode(&buf).unwrap().unwrap();
+            assert_matches!(value.nodes[0], Node::Leaf(Value::IntegerString(ref b))
+                if parse_int(b) == Some(*n));
+            assert_encodes_as(&read_value(&mut &buf[..]).unwrap(), &buf);
+        }
+        for raw in &[":99999999999999999999\r\n", ":9223372036854775808\r\n"] {
+            assert!(RedisValue::decode(raw).is_err(), "{:?}", raw);
+            assert!(read_value(&mut raw.as_bytes()).is_err(), "{:?}", raw);
+        }
+        assert!(RedisValue::decode(&"*2\r\n:1\r\n:-9223372036854775809\r\n").is_err());
+    }
+
+    #[test]
+    fn test_decode_failed_element_in_array() {
+        let failed_tests: Vec<&str> = vec![
+            "*2\r\n$3\r\nfoo\r\n!3\r\nbar\r\n",
+            "*2\r\n$3\r\nfoo\r\n$3\r\nbarr\r\n",
+            "*2\r\n$3\r\nfoo\r\n$x\r\nba",
+        ];
+        for raw in &failed_tests {
+            let result = decode_values_from_slice(raw.as_bytes());
+            assert_matches!(result, Err(DecodeError::Failed(_)));
+        }
+    }
+
+    #[test]
+    fn test_from_slices() {
+        let value = RedisValue::from_slices(&[b"SET", b"key", b"a\r\nb"]);
+
+        assert_eq!(value.nodes.len(), 5);
+        assert_matches!(value.nodes[0], Node::Open(3));
+        let args: Vec<&[u8]> = value.nodes[1..4]
+            .iter()
+            .map(|node| match node {
+                Node::Leaf(v @ Value::BulkString(_)) => v.as_slice(),
+                _ => panic!("expected a bulk string, got {:?}", node),
+            })
+            .collect();
+        assert_eq!(args, vec![&b"SET"[..], b"key", b"a\r\nb"]);
+        assert_matches!(value.nodes[4], Node::Close);
+
+        let mut buf = BytesMut::new();
+        value.encode(&mut buf);
+        assert_eq!(&buf[..], &b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$4\r\na\r\nb\r\n"[..]);
+
+        let mut buf = BytesMut::new();
+        RedisValue::from_slices(&[]).encode(&mut buf);
+        assert_eq!(&buf[..], b"*0\r\n");
+    }
+
+    #[test]
+    fn test_frame_len() {
+        assert_eq!(RedisValue::frame_len(&"+OK\r\n"), Ok(Some(5)));
+        assert_eq!(RedisValue::frame_len(&"$3\r\nfoo\r\n+OK\r\n"), Ok(Some(9)));
+        assert_eq!(
+            RedisValue::frame_len(&"*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n"),
+            Ok(Some(22))
+        );
+
+        assert_eq!(RedisValue::frame_len(&""), Ok(None));
+        assert_eq!(RedisValue::frame_len(&"$3\r\nfo"), Ok(None));
+        assert_eq!(RedisValue::frame_len(&"*2\r\n$3\r\nfoo\r\n"), Ok(None));
+
+        assert_eq!(RedisValue::frame_len(&"?3\r\nfoo\r\n"), Err(()));
+        assert_eq!(RedisValue::frame_len(&"$3\r\nfooo\r\n"), Err(()));
+        assert_eq!(RedisValue::frame_len(&"*x\r\n"), Err(()));
+    }
+
     #[test]
     fn test_decode_from_buffer() {
         let buf = Bytes::from("*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n");
@@ -475,4 +1069,169 @@ mod tests {
         //TODO: imple Eq for RedisValue
         assert_eq!(true, true);
     }
+
+    #[test]
+    fn test_size_matches_encoded_len() {
+        fn bulk(len: usize) -> Node<Bytes> {
+            Node::Leaf(Value::BulkString(Bytes::from(vec![b'x'; len])))
+        }
+        fn array(len: usize) -> Vec<Node<Bytes>> {
+            let mut nodes = vec![Node::Open(len)];
+            nodes.extend((0..len).map(|i| bulk(i % 12)));
+            nodes.push(Node::Close);
+            nodes
+        }
+
+        let mut battery: Vec<Vec<Node<Bytes>>> = vec![
+            vec![Node::Leaf(Value::Nil)],
+            vec![Node::Leaf(Value::SimpleString(Bytes::from("Ok")))],
+            vec![Node::Leaf(Value::SimpleString(Bytes::new()))],
+            vec![Node::Leaf(Value::from_error("ERR something"))],
+            vec![Node::Leaf(Value::from_integer(0))],
+            vec![Node::Leaf(Value::from_integer(i64::MIN))],
+            vec![Node::Leaf(Value::from_integer(i64::MAX))],
+            array(0),
+            array(1),
+            array(9),
+            array(10),
+            array(11),
+            array(100),
+            array(1000),
+        ];
+        for &len in &[0, 1, 9, 10, 11, 99, 100, 101, 9999, 10000, 100001] {
+            battery.push(vec![bulk(len)]);
+        }
+        // nested: [[], [nil, :1, [x * 10]], "xxx"]
+        let mut nested = vec![Node::Open(3)];
+        nested.extend(array(0));
+        nested.push(Node::Open(3));
+        nested.push(Node::Leaf(Value::Nil));
+        nested.push(Node::Leaf(Value::from_integer(1)));
+        nested.extend(array(10));
+        nested.push(Node::Close);
+        nested.push(bulk(3));
+        nested.push(Node::Close);
+        battery.push(nested);
+
+        for nodes in battery {
+            let value = RedisValue { nodes };
+            let mut buf = BytesMut::new();
+            value.encode(&mut buf);
+            assert_eq!(buf.len(), value.size(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_encode_line_breaks_replaced() {
+        let status = RedisValue::from_value(Value::SimpleString(Bytes::from("a\r\nb\n")));
+        assert_encodes_as(&status, b"+a  b \r\n");
+        let error = RedisValue::from_value(Value::from_error("ERR bad\r\n+OK"));
+        assert_encodes_as(&error, b"-ERR bad  +OK\r\n");
+        // the stream still holds exactly one reply
+        let mut buf = BytesMut::new();
+        error.encode(&mut buf);
+        assert_eq!(buf.len(), error.size());
+        assert_matches!(RedisValue::decode(&buf), Ok(Some((n, _))) if n == buf.len());
+    }
+
+    #[test]
+    fn test_read_value() {
+        let replies: &[&[u8]] = &[
+            b"+OK\r\n",
+            b"-ERR no\r\n",
+            b":-42\r\n",
+            b"$5\r\nhe\r\no\r\n",
+            b"$0\r\n\r\n",
+            b"$-1\r\n",
+            b"*0\r\n",
+            b"*-1\r\n",
+            b"*3\r\n:1\r\n*2\r\n$1\r\na\r\n*0\r\n$-1\r\n",
+        ];
+        // one reader over all of them, each read stops at its reply's end
+        let stream = replies.concat();
+        let mut r = &stream[..];
+        for &expected in replies {
+            assert_encodes_as(&read_value(&mut r).unwrap(), expected);
+        }
+        assert!(r.is_empty());
+
+        let read_err = |mut r: &[u8]| read_value(&mut r).unwrap_err().kind();
+        assert_eq!(read_err(b""), io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_err(b"*2\r\n:1\r\n"), io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_err(b"$5\r\nab"), io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_err(b"+OK\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(b"\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(b"*1\r\n\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(b":1x\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(b"$2\r\nabc\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(b"*-2\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(read_err(b"?\r\n"), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_encode_array_matches_materialized() {
+        let items = || (0..10_000).map(|i| Value::from_slice(format!("item:{}", i).as_bytes()));
+
+        let mut streamed = BytesMut::new();
+        RedisValue::encode_array(10_000, items(), &mut streamed);
+
+        let mut materialized = BytesMut::new();
+        let materialized_value = RedisValue::from_values(items().collect());
+        materialized_value.encode(&mut materialized);
+        assert_eq!(streamed, materialized);
+
+        // borrowed items, and the same bytes as a reply
+        let owned: Vec<Vec<u8>> = (0..10_000).map(|i| format!("item:{}", i).into_bytes()).collect();
+        let reply = RedisValue::streamed_array(owned.len(), owned.iter().map(Value::BulkString));
+        assert_eq!(reply.size(), materialized_value.size());
+        assert_encodes_as(&reply, &materialized);
+
+        assert_encodes_as(&RedisValue::streamed_array(0, Vec::<Value<Bytes>>::new()), b"*0\r\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "array of 2 items given 1")]
+    fn test_encode_array_wrong_count() {
+        RedisValue::encode_array(2, vec![Value::from_status("x")], &mut BytesMut::new());
+    }
+
+    #[test]
+    fn test_int_string_size() {
+        let numbers: Vec<i64> = vec![
+            0,
+            1,
+            9,
+            10,
+            99,
+            100,
+            999,
+            1000,
+            9999,
+            10000,
+            99999,
+            100000,
+            123456789,
+            -1,
+            -9,
+            -10,
+            -99,
+            -100,
+            -10000,
+            i64::from(i32::MAX),
+            i64::from(i32::MIN),
+            i64::MAX - 1,
+            i64::MAX,
+            i64::MIN + 1,
+            i64::MIN,
+        ];
+        for n in numbers {
+            assert_eq!(int_string_size(n), format!("{}", n).len(), "n = {}", n);
+        }
+        for exp in 0..19 {
+            let n = 10i64.pow(exp);
+            for &m in &[n - 1, n, n + 1, -n + 1, -n, -n - 1] {
+                assert_eq!(int_string_size(m), format!("{}", m).len(), "n = {}", m);
+            }
+        }
+    }
 }
@@ -1,37 +1,264 @@
 use std::io;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use futures::future;
 use tokio_service::Service;
 
-use super::redis_value::{RedisValue, Value};
+use super::redis_value::{Node, RedisValue, Value};
 use super::store::Store;
-use super::commands::parse_command;
+use super::commands::{parse_command_with_limits, ArgLimits, ClientCmd, Cmd, ParseError};
+use super::protocol::{Reply, Request};
+use super::stats::REJECTED_FRAMES;
+use super::monitor::{format_command, Monitors};
 
 pub struct RedisService {
     store: Arc<Store>,
+    monitors: Arc<Monitors>,
+    arg_limits: Arc<ArgLimits>,
 }
 impl RedisService {
+    #[cfg(test)]
     pub fn new(store: Arc<Store>) -> Self {
-        RedisService { store }
+        RedisService::with_monitors(store, Arc::new(Monitors::new()))
+    }
+    // A service whose commands are shown to, and whose MONITOR joins, the
+    // monitors shared with other connections
+    pub fn with_monitors(store: Arc<Store>, monitors: Arc<Monitors>) -> Self {
+        RedisService {
+            store,
+            monitors,
+            arg_limits: Arc::new(ArgLimits::default()),
+        }
+    }
+    // limits on the number of arguments commands may have, shared by all
+    // connections
+    pub fn with_arg_limits(mut self, arg_limits: Arc<ArgLimits>) -> Self {
+        self.arg_limits = arg_limits;
+        self
     }
 }
 
 impl Service for RedisService {
-    type Request = RedisValue;
-    type Response = RedisValue;
+    type Request = Request;
+    type Response = Reply;
     type Error = io::Error;
     // For simplicity, box the future.
-    type Future = future::FutureResult<RedisValue, io::Error>;
+    type Future = future::FutureResult<Reply, io::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        // formatted before parsing consumes the request
+        let line = if self.monitors.is_empty() {
+            None
+        } else {
+            let args: Vec<&[u8]> = req
+                .frame
+                .nodes
+                .iter()
+                .filter_map(|node| match node {
+                    Node::Leaf(v) => Some(v.as_slice()),
+                    _ => None,
+                })
+                .collect();
+            Some(format_command(req.client.addr, &args))
+        };
+
+        let reply = match parse_command_with_limits(req.frame.nodes, &self.arg_limits) {
+            Ok(Cmd::MONITOR) => Reply {
+                frames: vec![RedisValue::ok()],
+                feed: Some(self.monitors.subscribe()),
+            },
+            Ok(Cmd::CLIENT {
+                subcommand: ClientCmd::Id,
+            }) => Reply::from(RedisValue::from_value(Value::from(req.client.id as i64))),
+            Ok(cmd) => {
+                if let Some(line) = line {
+                    self.monitors.publish(line);
+                }
+                Reply::from(self.store.run_command(cmd))
+            }
+            Err(err) => {
+                REJECTED_FRAMES.fetch_add(1, Ordering::Relaxed);
+                Reply::from(parse_error_reply(err))
+            }
+        };
+
+        future::ok(reply)
+    }
+}
+
+fn parse_error_reply(err: ParseError) -> RedisValue {
+    let value = match err {
+        ParseError::NotImplemented => {
+            Value::from_error("ERR this command is not implemented in this build")
+        }
+        ParseError::NotInteger => Value::from_error("ERR value is not an integer or out of range"),
+        ParseError::InvalidExpireTime => Value::from_error("ERR invalid expire time"),
+        ParseError::TooManyArgs => Value::from_error("ERR too many arguments"),
+        ParseError::Syntax => Value::from_error("ERR syntax error"),
+        _ => Value::from_error("Error ocurred"),
+    };
+    RedisValue::from_value(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use futures::{Future, Stream};
+    use super::super::protocol::Client;
+
+    fn request(args: &[&[u8]], client: Client) -> Request {
+        Request {
+            frame: RedisValue::from_slices(args),
+            client,
+        }
+    }
+
+    // send a command through the service, returns the encoded reply frames
+    fn call(service: &RedisService, args: &[&str]) -> String {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        let response = service.call(request(&args, Client::default())).wait().unwrap();
+
+        let mut buf = BytesMut::new();
+        for frame in response.frames {
+            frame.encode(&mut buf);
+        }
+        String::from_utf8(buf.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_not_implemented_reply() {
+        let service = RedisService::new(Arc::new(Store::new()));
+
+        assert_eq!(
+            call(&service, &["MULTI"]),
+            "-ERR this command is not implemented in this build\r\n"
+        );
+        assert_eq!(
+            call(&service, &["publish", "news", "hello"]),
+            "-ERR this command is not implemented in this build\r\n"
+        );
+        assert_eq!(call(&service, &["NOPE"]), "-Error ocurred\r\n");
+    }
+
+    #[test]
+    fn test_mset_odd_arguments() {
+        let service = RedisService::new(Arc::new(Store::new()));
+
+        assert_eq!(call(&service, &["MSET", "a", "1", "b"]), "-Error ocurred\r\n");
+        assert_eq!(call(&service, &["MSET", "a"]), "-Error ocurred\r\n");
+        assert_eq!(call(&service, &["GET", "a"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn test_not_integer_reply() {
+        let service = RedisService::new(Arc::new(Store::new()));
+
+        assert_eq!(
+            call(&service, &["INCRBY", "n", "one"]),
+            "-ERR value is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            call(&service, &["DECRBY", "n", "9223372036854775808"]),
+            "-ERR value is not an integer or out of range\r\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_expire_time_reply() {
+        let service = RedisService::new(Arc::new(Store::new()));
+
+        assert_eq!(
+            call(&service, &["SET", "k", "v", "EX", "0"]),
+            "-ERR invalid expire time\r\n"
+        );
+        assert_eq!(
+            call(&service, &["SET", "k", "v", "PX", "-5"]),
+            "-ERR invalid expire time\r\n"
+        );
+        assert_eq!(call(&service, &["EXISTS", "k"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_syntax_error_reply() {
+        let service = RedisService::new(Arc::new(Store::new()));
+
+        assert_eq!(call(&service, &["SET", "k", "v", "EX"]), "-ERR syntax error\r\n");
+        assert_eq!(call(&service, &["SET", "k", "v", "NX", "XX"]), "-ERR syntax error\r\n");
+        assert_eq!(call(&service, &["SET", "k", "v", "BOGUS"]), "-ERR syntax error\r\n");
+        assert_eq!(call(&service, &["EXISTS", "k"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_arg_limits() {
+        let limits = ArgLimits::default().with_limit(b"del", 2);
+        let service = RedisService::new(Arc::new(Store::new())).with_arg_limits(Arc::new(limits));
+
+        assert_eq!(call(&service, &["DEL", "a", "b"]), ":0\r\n");
+        assert_eq!(call(&service, &["DEL", "a", "b", "c"]), "-ERR too many arguments\r\n");
+        assert_eq!(call(&service, &["EXISTS", "a", "b", "c"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_rejected_frames_counted() {
+        let service = RedisService::new(Arc::new(Store::new()));
+
+        let before = REJECTED_FRAMES.load(Ordering::Relaxed);
+        call(&service, &["NOPE"]);
+        assert!(REJECTED_FRAMES.load(Ordering::Relaxed) > before);
+    }
+
+    #[test]
+    fn test_client_id() {
+        let service = RedisService::new(Arc::new(Store::new()));
+        let client = Client {
+            id: 42,
+            addr: None,
+        };
+
+        let reply = service.call(request(&[b"client", b"id"], client)).wait().unwrap();
+        let mut buf = BytesMut::new();
+        reply.frames[0].encode(&mut buf);
+        assert_eq!(&buf[..], b":42\r\n");
+        assert_eq!(call(&service, &["CLIENT", "NOPE"]), "-Error ocurred\r\n");
+    }
+
+    #[test]
+    fn test_monitor() {
+        let store = Arc::new(Store::new());
+        let monitors = Arc::new(Monitors::new());
+        let monitor = RedisService::with_monitors(store.clone(), monitors.clone());
+        let other = RedisService::with_monitors(store.clone(), monitors.clone());
+        let client = Client {
+            id: 1,
+            addr: "127.0.0.1:5000".parse().ok(),
+        };
+
+        let reply = monitor.call(request(&[b"MONITOR"], client)).wait().unwrap();
+        assert_eq!(reply.frames.len(), 1);
+        let feed = reply.feed.unwrap();
+
+        other.call(request(&[b"SET", b"k", b"v"], client)).wait().unwrap();
+        // not run, so not shown
+        other.call(request(&[b"NOPE"], client)).wait().unwrap();
+        assert_eq!(call(&monitor, &["GET", "k"]), "$1\r\nv\r\n");
 
-    fn call(&self, req: RedisValue) -> Self::Future {
-        let response = parse_command(req.nodes)
-            .map(|cmd| self.store.run_command(cmd))
-            .unwrap_or_else(|_| {
-                let value = Value::from_error("Error ocurred");
-                RedisValue::from_value(value)
-            });
+        drop((monitor, other, monitors));
+        let lines: Vec<String> = feed
+            .wait()
+            .map(|frame| {
+                let mut buf = BytesMut::new();
+                frame.unwrap().encode(&mut buf);
+                String::from_utf8(buf.to_vec()).unwrap()
+            })
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with('+'));
+        assert!(lines[0].ends_with(" [0 127.0.0.1:5000] \"SET\" \"k\" \"v\"\r\n"));
+        assert!(lines[1].ends_with(" [0 unknown] \"GET\" \"k\"\r\n"));
 
-        future::ok(response)
+        // a service of its own has no one watching
+        assert!(RedisService::new(store).monitors.is_empty());
     }
 }
@@ -0,0 +1,10 @@
+use std::sync::atomic::AtomicUsize;
+
+// Counters reported in the INFO stats section. They count different
+// stages, so a bad request adds to exactly one of them:
+// - frames the codec failed to decode (malformed RESP, bad inline
+//   commands, frames over the decode limits); the connection is closed
+pub static PROTOCOL_ERRORS: AtomicUsize = AtomicUsize::new(0);
+// - frames that decoded fine but didn't parse into a supported command
+//   (unknown command, wrong number of arguments); the connection stays
+pub static REJECTED_FRAMES: AtomicUsize = AtomicUsize::new(0);
@@ -1,43 +1,2605 @@
-use std::sync::RwLock;
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::Range;
+use bytes::Bytes;
 
-use super::redis_value::{RedisValue, Value};
-use super::commands::Cmd;
+use super::redis_value::{parse_int, RedisValue, Value};
+use super::commands::{
+    command_spec, Cmd, Command, CommandSpec, DebugCmd, Latency, Memory, Object, SetCondition,
+    COMMANDS,
+};
+use super::stats::{PROTOCOL_ERRORS, REJECTED_FRAMES};
+use super::clock::{Clock, SystemClock};
 
 type Item = Vec<u8>;
 
+// A stored value, tagged with its type
+#[derive(Debug)]
+pub enum StoreValue {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+}
+
+// Error for a command run against a key holding another type of value
+#[derive(Debug, PartialEq)]
+pub struct WrongType;
+
+// Typed accessors, commands go through these (with ?) rather than matching
+// on StoreValue so a wrong-type key can't slip through
+impl StoreValue {
+    fn as_string(&self) -> Result<&Vec<u8>, WrongType> {
+        match self {
+            StoreValue::String(s) => Ok(s),
+            _ => Err(WrongType),
+        }
+    }
+    fn as_string_mut(&mut self) -> Result<&mut Vec<u8>, WrongType> {
+        match self {
+            StoreValue::String(s) => Ok(s),
+            _ => Err(WrongType),
+        }
+    }
+    fn into_string(self) -> Result<Vec<u8>, WrongType> {
+        match self {
+            StoreValue::String(s) => Ok(s),
+            _ => Err(WrongType),
+        }
+    }
+    fn as_list(&self) -> Result<&VecDeque<Vec<u8>>, WrongType> {
+        match self {
+            StoreValue::List(l) => Ok(l),
+            _ => Err(WrongType),
+        }
+    }
+    fn as_list_mut(&mut self) -> Result<&mut VecDeque<Vec<u8>>, WrongType> {
+        match self {
+            StoreValue::List(l) => Ok(l),
+            _ => Err(WrongType),
+        }
+    }
+    fn as_hash(&self) -> Result<&HashMap<Vec<u8>, Vec<u8>>, WrongType> {
+        match self {
+            StoreValue::Hash(h) => Ok(h),
+            _ => Err(WrongType),
+        }
+    }
+    fn as_hash_mut(&mut self) -> Result<&mut HashMap<Vec<u8>, Vec<u8>>, WrongType> {
+        match self {
+            StoreValue::Hash(h) => Ok(h),
+            _ => Err(WrongType),
+        }
+    }
+    fn as_set(&self) -> Result<&HashSet<Vec<u8>>, WrongType> {
+        match self {
+            StoreValue::Set(s) => Ok(s),
+            _ => Err(WrongType),
+        }
+    }
+    fn as_set_mut(&mut self) -> Result<&mut HashSet<Vec<u8>>, WrongType> {
+        match self {
+            StoreValue::Set(s) => Ok(s),
+            _ => Err(WrongType),
+        }
+    }
+    // strings may be empty, lists, hashes and sets never are: their key is
+    // deleted along with the last element
+    fn is_empty_collection(&self) -> bool {
+        match self {
+            StoreValue::String(_) => false,
+            StoreValue::List(l) => l.is_empty(),
+            StoreValue::Hash(h) => h.is_empty(),
+            StoreValue::Set(s) => s.is_empty(),
+        }
+    }
+    // bytes held, for a list or set the sum of its elements, for a hash of
+    // its fields and values
+    fn size(&self) -> usize {
+        match self {
+            StoreValue::String(s) => s.len(),
+            StoreValue::List(l) => l.iter().map(|v| v.len()).sum(),
+            StoreValue::Hash(h) => h.iter().map(|(f, v)| f.len() + v.len()).sum(),
+            StoreValue::Set(s) => s.iter().map(|m| m.len()).sum(),
+        }
+    }
+}
+
+// The type name reported for a value, shared by every command reporting
+// or filtering on types so they never disagree
+fn entry_type(value: &StoreValue) -> &'static str {
+    match value {
+        StoreValue::String(_) => "string",
+        StoreValue::List(_) => "list",
+        StoreValue::Hash(_) => "hash",
+        StoreValue::Set(_) => "set",
+    }
+}
+
+// A value and when it expires, if ever. Writes replacing the value (SET,
+// GETSET, ...) insert a new Record and so clear the expiry, in-place
+// updates (APPEND, INCR, ...) keep it
+struct Record {
+    value: StoreValue,
+    expires_at: Option<Instant>,
+}
+
+impl Record {
+    fn new(value: StoreValue) -> Self {
+        Record {
+            value,
+            expires_at: None,
+        }
+    }
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
 pub struct Store {
-    store: RwLock<HashMap<Item, Item>>,
+    store: RwLock<HashMap<Item, Record>>,
+    clock: Arc<dyn Clock>,
+    // whether the sweep thread evicts, see DEBUG SET-ACTIVE-EXPIRE
+    active_expire: AtomicBool,
+    // (deadline, key) for every expiry set, in deadline order, so a sweep
+    // finds the keys due without looking at the others. Entries are never
+    // updated: one whose key was since deleted, persisted or given another
+    // deadline is dropped by the sweep once its deadline passes
+    expiries: Mutex<BTreeSet<(Instant, Item)>>,
 }
 
 impl Store {
     pub fn new() -> Self {
+        Store::with_clock(Arc::new(SystemClock))
+    }
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Store {
             store: RwLock::new(HashMap::new()),
+            clock,
+            active_expire: AtomicBool::new(true),
+            expiries: Mutex::new(BTreeSet::new()),
         }
     }
+    // A store whose expired keys are also evicted by a background thread
+    // every interval, not only when touched. The thread holds the store
+    // weakly and exits once it's dropped. DEBUG SET-ACTIVE-EXPIRE 0 pauses
+    // it, leaving expired keys to lazy eviction alone
+    pub fn with_expiry_sweep(interval: Duration) -> Arc<Self> {
+        let store = Arc::new(Store::new());
+        let weak = Arc::downgrade(&store);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match weak.upgrade() {
+                Some(store) => {
+                    if store.active_expire.load(Ordering::Relaxed) {
+                        store.sweep_expired(SWEEP_MAX_KEYS);
+                    }
+                }
+                None => break,
+            }
+        });
+        store
+    }
+    // Takes up to max_keys entries whose deadline has passed off the
+    // expiry index, earliest first, and removes the keys that are still
+    // expired. Returns how many entries it took and how many keys it
+    // removed. Each sweep costs O(max_keys log n) however big the keyspace,
+    // the read lock is held only to check the entries taken, and the write
+    // lock (which blocks readers) only to remove keys
+    fn sweep_expired(&self, max_keys: usize) -> (usize, usize) {
+        let now = self.clock.now();
+        let mut due = Vec::new();
+        {
+            let mut expiries = self.expiries.lock().unwrap();
+            while due.len() < max_keys {
+                match expiries.first() {
+                    Some(&(at, _)) if at <= now => due.push(expiries.pop_first().unwrap().1),
+                    _ => break,
+                }
+            }
+        }
+        // the key may have been deleted, persisted or given a later
+        // deadline since the entry was added
+        let expired: Vec<Item> = {
+            let store = self.store.read().unwrap();
+            due.iter()
+                .filter(|key| store.get(&key[..]).is_some_and(|r| r.is_expired(now)))
+                .cloned()
+                .collect()
+        };
+        self.expire_keys(&expired);
+        (due.len(), expired.len())
+    }
+    // Adds key to the expiry index, for a record whose expiry was just set
+    // to at. Called with the store's write lock held, which is always taken
+    // before the index's
+    fn index_expiry(&self, key: &[u8], at: Instant) {
+        self.expiries.lock().unwrap().insert((at, key.to_vec()));
+    }
+    // Expired keys are removed lazily, when a command touches them. Only
+    // takes the write lock if one of keys has actually expired
+    fn expire_keys<K: AsRef<[u8]>>(&self, keys: &[K]) {
+        let now = self.clock.now();
+        let expired = |store: &HashMap<Item, Record>, key: &[u8]| {
+            store.get(key).is_some_and(|r| r.is_expired(now))
+        };
+        if !keys.iter().any(|k| expired(&self.store.read().unwrap(), k.as_ref())) {
+            return;
+        }
+        let mut store = self.store.write().unwrap();
+        for key in keys {
+            if expired(&store, key.as_ref()) {
+                store.remove(key.as_ref());
+            }
+        }
+    }
+    // Number of keys, counting expired keys until they are removed (like
+    // redis' DBSIZE); the map keeps its length so this is O(1)
+    pub fn len(&self) -> usize {
+        self.store.read().unwrap().len()
+    }
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // Runs f with the string at key (None when missing) under a single
+    // write lock, for atomic read-modify-write operations like APPEND and
+    // INCR. f may fill in a missing key to create it, or set it to None to
+    // delete it; an existing key keeps its expiry. WrongType, without
+    // running f, when key holds another type
+    pub fn update<F, R>(&self, key: &[u8], f: F) -> Result<R, WrongType>
+    where
+        F: FnOnce(&mut Option<Vec<u8>>) -> R,
+    {
+        self.expire_keys(&[key]);
+        let mut store = self.store.write().unwrap();
+        let mut value = match store.get_mut(key) {
+            Some(record) => Some(mem::take(record.value.as_string_mut()?)),
+            None => None,
+        };
+        let result = f(&mut value);
+        match (value, store.get_mut(key)) {
+            (Some(value), Some(record)) => record.value = StoreValue::String(value),
+            (Some(value), None) => {
+                store.insert(key.to_vec(), Record::new(StoreValue::String(value)));
+            }
+            (None, Some(_)) => {
+                store.remove(key);
+            }
+            (None, None) => {}
+        }
+        Ok(result)
+    }
+    // Reads all keys under a single read lock, results are in the order of
+    // keys, None for missing (or non-string) ones
+    pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<Option<Bytes>> {
+        self.expire_keys(keys);
+        let store = self.store.read().unwrap();
+        keys.iter()
+            .map(|k| {
+                let value = store.get(k.as_ref()).and_then(|r| r.value.as_string().ok());
+                value.map(|v| Bytes::from(&v[..]))
+            })
+            .collect()
+    }
+    // Type name of the value at key, "none" when missing
+    pub fn key_type(&self, key: &[u8]) -> &'static str {
+        self.expire_keys(&[key]);
+        let store = self.store.read().unwrap();
+        store.get(key).map_or("none", |r| entry_type(&r.value))
+    }
+    // Size in bytes of the largest value currently stored, recomputed on
+    // each call so every write path is accounted for
+    pub fn largest_value(&self) -> usize {
+        let now = self.clock.now();
+        let store = self.store.read().unwrap();
+        store
+            .values()
+            .filter(|r| !r.is_expired(now))
+            .map(|r| r.value.size())
+            .max()
+            .unwrap_or(0)
+    }
+    // A digest of every live key and its value, equal for two stores holding
+    // the same data whatever order it was written in: each entry is hashed
+    // on its own and the results XORed, as are a hash's fields and a set's
+    // members within their entry. Expiry times are left out. Shown by
+    // DEBUG DIGEST
+    pub fn checksum(&self) -> u64 {
+        let now = self.clock.now();
+        let store = self.store.read().unwrap();
+        store
+            .iter()
+            .filter(|&(_, r)| !r.is_expired(now))
+            .map(|(k, r)| entry_hash(k, &r.value))
+            .fold(0, |sum, h| sum ^ h)
+    }
+    // Returns up to count keys (a few more on hash collisions) whose scan
+    // hash is at or after cursor, and the cursor to continue from (0 when
+    // done). Keys are visited in the order of a stable hash of their
+    // contents, which insertions, deletions and rehashing of the map don't
+    // change, so a key present for the whole scan is returned at least once.
+    // Expired keys are dropped from the batch
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Item>) {
+        let now = self.clock.now();
+        let store = self.store.read().unwrap();
+        let (next_cursor, batch) = scan_batch(store.keys(), cursor, count);
+        let keys = batch
+            .into_iter()
+            .filter(|&k| !store[k].is_expired(now))
+            .cloned()
+            .collect();
+        (next_cursor, keys)
+    }
+    // All live keys matching a glob pattern, in no particular order
+    pub fn keys(&self, pattern: &[u8]) -> Vec<Item> {
+        let now = self.clock.now();
+        let store = self.store.read().unwrap();
+        store
+            .iter()
+            .filter(|&(k, r)| !r.is_expired(now) && glob_match(pattern, k))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
     pub fn run_command<T: AsRef<[u8]>>(&self, cmd: Cmd<Value<T>>) -> RedisValue {
-        match cmd {
+        self.execute(cmd)
+            .unwrap_or_else(|WrongType| error_reply(ERR_WRONGTYPE))
+    }
+
+    fn execute<T: AsRef<[u8]>>(&self, cmd: Cmd<Value<T>>) -> Result<RedisValue, WrongType> {
+        // commands then see the keys they touch as of now
+        self.expire_keys(&cmd.keys().iter().map(|k| k.as_slice()).collect::<Vec<_>>());
+        #[cfg(debug_assertions)]
+        let touched: Vec<Item> = cmd.keys().iter().map(|k| k.as_slice().to_vec()).collect();
+
+        let reply = match cmd {
             Cmd::GET { key } => {
                 let store = self.store.read().unwrap();
-                let value = store.get(key.as_slice());
+                let value = store.get(key.as_slice()).map(|r| r.value.as_string()).transpose()?;
                 let value = value.map_or(Value::Nil, |s| Value::from_slice(s));
                 RedisValue::from_value(value)
             }
+            Cmd::GETDEL { key } => {
+                let mut store = self.store.write().unwrap();
+                if let Some(record) = store.get(key.as_slice()) {
+                    record.value.as_string()?;
+                }
+                let value = store.remove(key.as_slice()).map(|r| r.value.into_string());
+                RedisValue::from_value(Value::from(value.transpose()?))
+            }
+            Cmd::INFO { section } => {
+                let section = section.map(|s| s.as_slice().to_ascii_lowercase());
+                let info = self.info(section.as_ref().map(|s| &s[..]));
+                RedisValue::from_value(Value::BulkString(Bytes::from(info)))
+            }
+            Cmd::MEMORY {
+                subcommand: Memory::Stats,
+            } => {
+                let keys_count = self.len();
+                RedisValue::from_values(vec![
+                    Value::from_slice(b"keys.count"),
+                    Value::from_integer(keys_count as i64),
+                    Value::from_slice(b"largest.value.bytes"),
+                    Value::from_integer(self.largest_value() as i64),
+                ])
+            }
+            Cmd::SCAN { cursor, count } => {
+                let (cursor, keys) = self.scan(cursor, count);
+                let keys = keys
+                    .into_iter()
+                    .map(|k| Value::BulkString(Bytes::from(k)))
+                    .collect();
+                RedisValue::array(vec![
+                    RedisValue::from_value(Value::from_slice(cursor.to_string().as_bytes())),
+                    RedisValue::from_values(keys),
+                ])
+            }
+            Cmd::KEYS { pattern } => {
+                let keys = self
+                    .keys(pattern.as_slice())
+                    .into_iter()
+                    .map(|k| Value::BulkString(Bytes::from(k)))
+                    .collect();
+                RedisValue::from_values(keys)
+            }
+            Cmd::PING { message } => match message {
+                Some(message) => RedisValue::from_value(Value::BulkString(Bytes::from(
+                    message.as_slice(),
+                ))),
+                None => RedisValue::from_value(Value::from_status("PONG")),
+            },
+            // like PING, answered here without touching the map so every
+            // command goes through one dispatch
+            Cmd::ECHO { message } => {
+                RedisValue::from_value(Value::BulkString(Bytes::from(message.as_slice())))
+            }
+            Cmd::LOLWUT => {
+                let art = format!("{}toy-redis ver. {}\n", LOLWUT_ART, env!("CARGO_PKG_VERSION"));
+                RedisValue::from_value(Value::BulkString(Bytes::from(art)))
+            }
+            Cmd::EXPIRE { key, expire } => {
+                let expires_at = match self.clock.now().checked_add(expire) {
+                    Some(t) => t,
+                    None => return Ok(error_reply(ERR_INVALID_EXPIRE)),
+                };
+                let mut store = self.store.write().unwrap();
+                let updated = match store.get_mut(key.as_slice()) {
+                    Some(record) => {
+                        record.expires_at = Some(expires_at);
+                        self.index_expiry(key.as_slice(), expires_at);
+                        1
+                    }
+                    None => 0,
+                };
+                RedisValue::from_value(Value::from_integer(updated))
+            }
+            Cmd::TTL { key } => {
+                let now = self.clock.now();
+                let store = self.store.read().unwrap();
+                let ttl = match store.get(key.as_slice()) {
+                    None => -2,
+                    Some(Record {
+                        expires_at: None, ..
+                    }) => -1,
+                    // a key expiring since the command started counts as gone
+                    Some(Record {
+                        expires_at: Some(t),
+                        ..
+                    }) => match t.checked_duration_since(now) {
+                        // rounded to the nearest second, like redis
+                        Some(left) => ((left.as_millis() + 500) / 1000) as i64,
+                        None => -2,
+                    },
+                };
+                RedisValue::from_value(Value::from_integer(ttl))
+            }
+            Cmd::PERSIST { key } => {
+                let mut store = self.store.write().unwrap();
+                let removed = store
+                    .get_mut(key.as_slice())
+                    .and_then(|record| record.expires_at.take())
+                    .map_or(0, |_| 1);
+                RedisValue::from_value(Value::from_integer(removed))
+            }
+            Cmd::DBSIZE => {
+                let len = self.len();
+                RedisValue::from_value(Value::from_integer(len as i64))
+            }
+            // with no replicas to wait for, none have acknowledged and
+            // there's nothing to block on until the timeout
+            Cmd::WAIT => RedisValue::from_value(Value::from_integer(0)),
+            // the connection streaming the feed is set up by RedisService,
+            // the store only acknowledges
+            Cmd::MONITOR => RedisValue::ok(),
+            // only the connection knows its id
+            Cmd::CLIENT { .. } => error_reply(ERR_NO_CONNECTION),
+            Cmd::FLUSHDB => {
+                self.store.write().unwrap().clear();
+                RedisValue::ok()
+            }
+            Cmd::COMMAND {
+                subcommand: Command::All,
+            } => RedisValue::array(COMMANDS.iter().map(command_reply).collect()),
+            Cmd::COMMAND {
+                subcommand: Command::Info(names),
+            } => {
+                let replies = names
+                    .iter()
+                    .map(|name| match command_spec(name.as_slice()) {
+                        Some(spec) => command_reply(spec),
+                        None => RedisValue::from_value(Value::Nil),
+                    })
+                    .collect();
+                RedisValue::array(replies)
+            }
+            Cmd::TYPE { key } => {
+                RedisValue::from_value(Value::from(self.key_type(key.as_slice())))
+            }
+            Cmd::STRLEN { key } => {
+                let store = self.store.read().unwrap();
+                let value = store.get(key.as_slice()).map(|r| r.value.as_string()).transpose()?;
+                let len = value.map_or(0, |v| v.len());
+                RedisValue::from_value(Value::from_integer(len as i64))
+            }
+            Cmd::GETRANGE { key, start, end } => {
+                let store = self.store.read().unwrap();
+                let value = store.get(key.as_slice()).map(|r| r.value.as_string()).transpose()?;
+                let value = value.map_or(&[][..], |v| &v[..]);
+                let range = index_range(value.len(), start, end).unwrap_or(0..0);
+                RedisValue::from_value(Value::BulkString(Bytes::from(&value[range])))
+            }
+            Cmd::SETRANGE { key, offset, value } => {
+                let value = value.as_slice();
+                let offset = match offset {
+                    n if n < 0 => return Ok(error_reply(ERR_OFFSET)),
+                    n if n as u64 + value.len() as u64 > MAX_STRING_SIZE => {
+                        return Ok(error_reply(ERR_STRING_SIZE))
+                    }
+                    n => n as usize,
+                };
+                self.update(key.as_slice(), |stored| {
+                    // like redis, an empty write neither creates nor pads
+                    if value.is_empty() {
+                        let len = stored.as_ref().map_or(0, |v| v.len());
+                        return RedisValue::from_value(Value::from_integer(len as i64));
+                    }
+                    let stored = stored.get_or_insert_with(Vec::new);
+                    let end = offset + value.len();
+                    if stored.len() < end {
+                        stored.resize(end, 0);
+                    }
+                    stored[offset..end].copy_from_slice(value);
+                    RedisValue::from_value(Value::from_integer(stored.len() as i64))
+                })?
+            }
+            Cmd::APPEND { key, value } => self.update(key.as_slice(), |stored| {
+                let stored = stored.get_or_insert_with(Vec::new);
+                append(stored, value.as_slice());
+                RedisValue::from_value(Value::from_integer(stored.len() as i64))
+            })?,
+            Cmd::LPUSH { key, values } => self.push(key.as_slice(), &values, true)?,
+            Cmd::RPUSH { key, values } => self.push(key.as_slice(), &values, false)?,
+            Cmd::LPOP { key, count } => self.pop(key.as_slice(), count, true)?,
+            Cmd::RPOP { key, count } => self.pop(key.as_slice(), count, false)?,
+            Cmd::LRANGE { key, start, end } => {
+                let store = self.store.read().unwrap();
+                let list = store.get(key.as_slice()).map(|r| r.value.as_list()).transpose()?;
+                let range = list.and_then(|list| index_range(list.len(), start, end));
+                match (list, range) {
+                    (Some(list), Some(range)) => {
+                        let n = range.len();
+                        RedisValue::streamed_array(n, list.range(range).map(Value::BulkString))
+                    }
+                    _ => RedisValue::from_values(vec![]),
+                }
+            }
+            Cmd::HSET { key, pairs } => {
+                let mut store = self.store.write().unwrap();
+                let hash = store
+                    .entry(key.as_slice().to_vec())
+                    .or_insert_with(|| Record::new(StoreValue::Hash(HashMap::new())))
+                    .value
+                    .as_hash_mut()?;
+                // only fields that didn't exist yet count
+                let mut created = 0;
+                for (field, value) in pairs {
+                    let field = field.as_slice().to_vec();
+                    if hash.insert(field, value.as_slice().to_vec()).is_none() {
+                        created += 1;
+                    }
+                }
+                RedisValue::from_value(Value::from(created))
+            }
+            Cmd::HGET { key, field } => {
+                let store = self.store.read().unwrap();
+                let hash = store.get(key.as_slice()).map(|r| r.value.as_hash()).transpose()?;
+                let value = hash.and_then(|h| h.get(field.as_slice()));
+                RedisValue::from_value(Value::from(value.cloned()))
+            }
+            Cmd::HDEL { key, fields } => {
+                let mut store = self.store.write().unwrap();
+                let removed = match store.get_mut(key.as_slice()) {
+                    Some(record) => {
+                        let hash = record.value.as_hash_mut()?;
+                        fields
+                            .iter()
+                            .filter(|f| hash.remove(f.as_slice()).is_some())
+                            .count()
+                    }
+                    None => 0,
+                };
+                remove_if_empty(&mut store, key.as_slice());
+                RedisValue::from_value(Value::from(removed as i64))
+            }
+            Cmd::HGETALL { key } => {
+                let store = self.store.read().unwrap();
+                let hash = store.get(key.as_slice()).map(|r| r.value.as_hash()).transpose()?;
+                // field, value, field, value, ...
+                let n = hash.map_or(0, |h| h.len() * 2);
+                let items = hash
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|(f, v)| [Value::BulkString(f), Value::BulkString(v)]);
+                RedisValue::streamed_array(n, items)
+            }
+            Cmd::HKEYS { key } => {
+                let store = self.store.read().unwrap();
+                let hash = store.get(key.as_slice()).map(|r| r.value.as_hash()).transpose()?;
+                let fields = hash.into_iter().flat_map(|h| h.keys());
+                RedisValue::from_values(fields.map(|f| Value::from(f.clone())).collect())
+            }
+            Cmd::HVALS { key } => {
+                let store = self.store.read().unwrap();
+                let hash = store.get(key.as_slice()).map(|r| r.value.as_hash()).transpose()?;
+                let values = hash.into_iter().flat_map(|h| h.values());
+                RedisValue::from_values(values.map(|v| Value::from(v.clone())).collect())
+            }
+            Cmd::SADD { key, members } => {
+                let mut store = self.store.write().unwrap();
+                let set = store
+                    .entry(key.as_slice().to_vec())
+                    .or_insert_with(|| Record::new(StoreValue::Set(HashSet::new())))
+                    .value
+                    .as_set_mut()?;
+                let added = members
+                    .iter()
+                    .filter(|m| set.insert(m.as_slice().to_vec()))
+                    .count();
+                RedisValue::from_value(Value::from(added as i64))
+            }
+            Cmd::SREM { key, members } => {
+                let mut store = self.store.write().unwrap();
+                let removed = match store.get_mut(key.as_slice()) {
+                    Some(record) => {
+                        let set = record.value.as_set_mut()?;
+                        members.iter().filter(|m| set.remove(m.as_slice())).count()
+                    }
+                    None => 0,
+                };
+                remove_if_empty(&mut store, key.as_slice());
+                RedisValue::from_value(Value::from(removed as i64))
+            }
+            Cmd::SMEMBERS { key } => {
+                let store = self.store.read().unwrap();
+                let set = store.get(key.as_slice()).map(|r| r.value.as_set()).transpose()?;
+                let members = set.into_iter().flatten();
+                RedisValue::from_values(members.map(|m| Value::from(m.clone())).collect())
+            }
+            Cmd::SISMEMBER { key, member } => {
+                let store = self.store.read().unwrap();
+                let set = store.get(key.as_slice()).map(|r| r.value.as_set()).transpose()?;
+                let is_member = set.is_some_and(|s| s.contains(member.as_slice()));
+                RedisValue::from_value(Value::from(is_member))
+            }
+            Cmd::SCARD { key } => {
+                let store = self.store.read().unwrap();
+                let set = store.get(key.as_slice()).map(|r| r.value.as_set()).transpose()?;
+                RedisValue::from_value(Value::from(set.map_or(0, |s| s.len()) as i64))
+            }
+            Cmd::SINTER { keys } => {
+                let store = self.store.read().unwrap();
+                // keys are checked in order, like redis: a missing key ends
+                // the command with an empty result before later keys' types
+                // are looked at
+                let mut sets = Vec::with_capacity(keys.len());
+                for key in &keys {
+                    match store.get(key.as_slice()) {
+                        Some(record) => sets.push(record.value.as_set()?),
+                        None => return Ok(RedisValue::from_values(vec![])),
+                    }
+                }
+                // only the smallest set's members can be in all of them
+                sets.sort_by_key(|s| s.len());
+                let (smallest, rest) = sets.split_first().unwrap();
+                let members = smallest
+                    .iter()
+                    .filter(|m| rest.iter().all(|s| s.contains(*m)))
+                    .map(|m| Value::from(m.clone()))
+                    .collect();
+                RedisValue::from_values(members)
+            }
+            Cmd::INCR { key } => self.incr_by(key.as_slice(), 1)?,
+            Cmd::DECR { key } => self.incr_by(key.as_slice(), -1)?,
+            Cmd::INCRBY { key, delta } => self.incr_by(key.as_slice(), delta)?,
+            Cmd::DECRBY { key, delta } => match delta.checked_neg() {
+                Some(delta) => self.incr_by(key.as_slice(), delta)?,
+                None => error_reply(ERR_OVERFLOW),
+            },
             Cmd::DEL { keys } => {
                 let mut store = self.store.write().unwrap();
-                let deleted: usize = (&keys)
+                let deleted: usize = keys
                     .iter()
                     .map(|k| store.remove(k.as_slice()).map_or(0, |_| 1))
                     .sum();
 
                 RedisValue::from_value(Value::from_integer(deleted as i64))
             }
-            Cmd::SET { key, value } => {
+            Cmd::MGET { keys } => {
+                let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+                let values = self
+                    .get_many(&keys)
+                    .into_iter()
+                    .map(Value::from)
+                    .collect();
+                RedisValue::from_values(values)
+            }
+            Cmd::EXISTS { keys } => {
+                let store = self.store.read().unwrap();
+                // every occurrence counts, EXISTS k k is 2 if k exists
+                let count = keys
+                    .iter()
+                    .filter(|k| store.contains_key(k.as_slice()))
+                    .count();
+                RedisValue::from_value(Value::from_integer(count as i64))
+            }
+            Cmd::SET {
+                key,
+                value,
+                expire,
+                condition,
+            } => {
+                let expires_at = match expire {
+                    Some(expire) => match self.clock.now().checked_add(expire) {
+                        Some(t) => Some(t),
+                        None => return Ok(error_reply(ERR_INVALID_EXPIRE)),
+                    },
+                    None => None,
+                };
+                let mut store = self.store.write().unwrap();
+                let exists = store.contains_key(key.as_slice());
+                let write = match condition {
+                    None => true,
+                    Some(SetCondition::NotExists) => !exists,
+                    Some(SetCondition::Exists) => exists,
+                };
+                if write {
+                    let value = StoreValue::String(value.as_slice().to_vec());
+                    store.insert(key.as_slice().to_vec(), Record { value, expires_at });
+                    if let Some(at) = expires_at {
+                        self.index_expiry(key.as_slice(), at);
+                    }
+                    RedisValue::ok()
+                } else {
+                    RedisValue::from_value(Value::Nil)
+                }
+            }
+            Cmd::SETNX { key, value } => {
+                let mut store = self.store.write().unwrap();
+                let written = match store.entry(key.as_slice().to_vec()) {
+                    Entry::Occupied(_) => 0,
+                    Entry::Vacant(entry) => {
+                        entry.insert(Record::new(StoreValue::String(value.as_slice().to_vec())));
+                        1
+                    }
+                };
+                RedisValue::from_value(Value::from_integer(written))
+            }
+            Cmd::RENAME { key, newkey } => {
                 let mut store = self.store.write().unwrap();
-                store.insert(key.as_slice().to_vec(), value.as_slice().to_vec());
+                match store.remove(key.as_slice()) {
+                    Some(record) => {
+                        // the expiry moves with the value
+                        if let Some(at) = record.expires_at {
+                            self.index_expiry(newkey.as_slice(), at);
+                        }
+                        store.insert(newkey.as_slice().to_vec(), record);
+                        RedisValue::ok()
+                    }
+                    None => error_reply(ERR_NO_SUCH_KEY),
+                }
+            }
+            Cmd::RENAMENX { key, newkey } => {
+                let mut store = self.store.write().unwrap();
+                if !store.contains_key(key.as_slice()) {
+                    error_reply(ERR_NO_SUCH_KEY)
+                } else if store.contains_key(newkey.as_slice()) {
+                    RedisValue::from_value(Value::from_integer(0))
+                } else {
+                    let record = store.remove(key.as_slice()).unwrap();
+                    if let Some(at) = record.expires_at {
+                        self.index_expiry(newkey.as_slice(), at);
+                    }
+                    store.insert(newkey.as_slice().to_vec(), record);
+                    RedisValue::from_value(Value::from_integer(1))
+                }
+            }
+            Cmd::GETSET { key, value } => {
+                let mut store = self.store.write().unwrap();
+                if let Some(old) = store.get(key.as_slice()) {
+                    old.value.as_string()?;
+                }
+                let old = store.insert(
+                    key.as_slice().to_vec(),
+                    Record::new(StoreValue::String(value.as_slice().to_vec())),
+                );
+                let old = old.map(|r| r.value.into_string()).transpose()?;
+                RedisValue::from_value(Value::from(old))
+            }
+            Cmd::MSET { pairs } => {
+                let mut store = self.store.write().unwrap();
+                for (key, value) in pairs {
+                    store.insert(
+                        key.as_slice().to_vec(),
+                        Record::new(StoreValue::String(value.as_slice().to_vec())),
+                    );
+                }
+                RedisValue::ok()
+            }
+            Cmd::OBJECT {
+                subcommand: Object::Encoding,
+                key,
+            } => {
+                let store = self.store.read().unwrap();
+                let value = store.get(key.as_slice());
+                let value = value.map_or(Value::Nil, |r| match &r.value {
+                    StoreValue::String(s) => Value::from_slice(encoding(s)),
+                    StoreValue::List(l) => Value::from_slice(list_encoding(l)),
+                    StoreValue::Hash(h) => Value::from_slice(hash_encoding(h)),
+                    StoreValue::Set(s) => Value::from_slice(set_encoding(s)),
+                });
+                RedisValue::from_value(value)
+            }
+            Cmd::DEBUG {
+                subcommand: DebugCmd::Noop,
+            } => RedisValue::ok(),
+            Cmd::DEBUG {
+                subcommand: DebugCmd::Digest,
+            } => {
+                let digest = format!("{:016x}", self.checksum());
+                RedisValue::from_value(Value::from_status(&digest))
+            }
+            Cmd::DEBUG {
+                subcommand: DebugCmd::SetActiveExpire(enabled),
+            } => {
+                self.active_expire.store(enabled, Ordering::Relaxed);
                 RedisValue::ok()
             }
+            // no latency monitoring: nothing recorded, nothing to reset
+            Cmd::LATENCY { subcommand } => match subcommand {
+                Latency::History | Latency::Latest => RedisValue::from_values(vec![]),
+                Latency::Reset => RedisValue::from_value(Value::from_integer(0)),
+            },
+        };
+
+        #[cfg(debug_assertions)]
+        self.assert_no_empty_collections(&touched);
+        Ok(reply)
+    }
+
+    // A command emptying a collection must delete its key (remove_if_empty)
+    // under the same lock, checked after every command in debug builds
+    #[cfg(debug_assertions)]
+    fn assert_no_empty_collections(&self, keys: &[Item]) {
+        let store = self.store.read().unwrap();
+        for key in keys {
+            if let Some(record) = store.get(key) {
+                assert!(
+                    !record.value.is_empty_collection(),
+                    "empty {} left at {:?}",
+                    entry_type(&record.value),
+                    String::from_utf8_lossy(key)
+                );
+            }
+        }
+    }
+
+    // INFO reply for the given (lowercase) section, None for the default
+    // sections; an unknown section gives an empty reply
+    fn info(&self, section: Option<&[u8]>) -> String {
+        let mut info = String::new();
+        let all = matches!(
+            section,
+            None | Some(b"default") | Some(b"all") | Some(b"everything")
+        );
+        if all || section == Some(b"memory") {
+            info.push_str("# Memory\r\n");
+            info.push_str(&format!("largest_value_bytes:{}\r\n", self.largest_value()));
+        }
+        if all || section == Some(b"stats") {
+            // separate stages, a bad request counts in only one (see stats)
+            let protocol_errors = PROTOCOL_ERRORS.load(Ordering::Relaxed);
+            let rejected_frames = REJECTED_FRAMES.load(Ordering::Relaxed);
+            info.push_str("# Stats\r\n");
+            info.push_str(&format!("protocol_errors:{}\r\n", protocol_errors));
+            info.push_str(&format!("rejected_frames:{}\r\n", rejected_frames));
+        }
+        info
+    }
+
+    // pushes values one at a time onto the head (or tail) of the list at
+    // key, creating it if missing, so LPUSH k a b leaves b first
+    fn push<T: AsRef<[u8]>>(
+        &self,
+        key: &[u8],
+        values: &[Value<T>],
+        front: bool,
+    ) -> Result<RedisValue, WrongType> {
+        let mut store = self.store.write().unwrap();
+        let list = store
+            .entry(key.to_vec())
+            .or_insert_with(|| Record::new(StoreValue::List(VecDeque::new())))
+            .value
+            .as_list_mut()?;
+        for value in values {
+            let value = value.as_slice().to_vec();
+            if front {
+                list.push_front(value);
+            } else {
+                list.push_back(value);
+            }
+        }
+        Ok(RedisValue::from_value(Value::from_integer(list.len() as i64)))
+    }
+
+    // Pops from the head (or tail) of the list at key. Without a count
+    // that's one element or nil, with one an array of up to count elements
+    // in the order popped, a null array when the key is missing
+    fn pop(&self, key: &[u8], count: Option<usize>, front: bool) -> Result<RedisValue, WrongType> {
+        let mut store = self.store.write().unwrap();
+        let popped: Option<Vec<Item>> = match store.get_mut(key) {
+            Some(record) => {
+                let list = record.value.as_list_mut()?;
+                let len = list.len();
+                let n = count.unwrap_or(1).min(len);
+                if front {
+                    Some(list.drain(..n).collect())
+                } else {
+                    Some(list.drain(len - n..).rev().collect())
+                }
+            }
+            None => None,
+        };
+        remove_if_empty(&mut store, key);
+        Ok(match (count, popped) {
+            (None, popped) => {
+                RedisValue::from_value(Value::from(popped.and_then(|p| p.into_iter().next())))
+            }
+            (Some(_), Some(popped)) => {
+                RedisValue::from_values(popped.into_iter().map(Value::from).collect())
+            }
+            (Some(_), None) => RedisValue::from_value(Value::NilArray),
+        })
+    }
+
+    // adds delta to the integer stored at key, a missing key counts as 0
+    fn incr_by(&self, key: &[u8], delta: i64) -> Result<RedisValue, WrongType> {
+        self.update(key, |value| {
+            let result = match value {
+                Some(value) => match parse_int(value) {
+                    Some(n) => n.checked_add(delta),
+                    None => return error_reply(ERR_NOT_INTEGER),
+                },
+                None => Some(delta),
+            };
+            match result {
+                Some(n) => {
+                    *value = Some(n.to_string().into_bytes());
+                    RedisValue::from_value(Value::from_integer(n))
+                }
+                None => error_reply(ERR_OVERFLOW),
+            }
+        })
+    }
+}
+
+// Deletes key if it holds a collection left empty, like redis. Every
+// command removing elements calls this under the write lock it removed
+// them with, so no other command sees the empty collection
+fn remove_if_empty(store: &mut HashMap<Item, Record>, key: &[u8]) {
+    if store.get(key).is_some_and(|r| r.value.is_empty_collection()) {
+        store.remove(key);
+    }
+}
+
+// most keys a single sweep examines, like redis' active expire cycle
+const SWEEP_MAX_KEYS: usize = 20;
+
+// The next batch of about count keys from cursor on in scan_hash order,
+// and the cursor after it. Every call still hashes every key, so it costs
+// O(N + count log count) for N keys and a whole scan O(N^2 / count): only
+// the keys of the batch are sorted, the rest are just partitioned off
+fn scan_batch<'a, I>(items: I, cursor: u64, count: usize) -> (u64, Vec<&'a Item>)
+where
+    I: Iterator<Item = &'a Item>,
+{
+    let mut candidates: Vec<(u64, &Item)> = items
+        .map(|k| (scan_hash(k), k))
+        .filter(|&(h, _)| h >= cursor)
+        .collect();
+
+    let mut end = count.max(1).min(candidates.len());
+    if end < candidates.len() {
+        // the end smallest first, in no particular order
+        candidates.select_nth_unstable(end - 1);
+        // never split items sharing a hash across calls
+        let last = candidates[end - 1].0;
+        let mut i = end;
+        while i < candidates.len() {
+            if candidates[i].0 == last {
+                candidates.swap(i, end);
+                end += 1;
+            }
+            i += 1;
+        }
+    }
+    let next_cursor = if end < candidates.len() {
+        candidates[end - 1].0.checked_add(1).unwrap_or(0)
+    } else {
+        0
+    };
+    candidates.truncate(end);
+    candidates.sort_unstable();
+    (next_cursor, candidates.into_iter().map(|(_, k)| k).collect())
+}
+
+fn entry_hash(key: &[u8], value: &StoreValue) -> u64 {
+    let unordered = |hashes: &mut dyn Iterator<Item = u64>| hashes.fold(0, |sum, h| sum ^ h);
+    let contents = match value {
+        StoreValue::String(s) => stable_hash(s),
+        StoreValue::List(l) => stable_hash(l),
+        StoreValue::Hash(h) => unordered(&mut h.iter().map(stable_hash)),
+        StoreValue::Set(s) => unordered(&mut s.iter().map(stable_hash)),
+    };
+    stable_hash((key, entry_type(value), contents))
+}
+
+fn stable_hash<T: Hash>(value: T) -> u64 {
+    // DefaultHasher::new always uses the same keys, so the hash of a value
+    // is stable across calls
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn scan_hash(key: &[u8]) -> u64 {
+    stable_hash(key)
+}
+
+// Glob matching as in redis: * and ? wildcards, [abc] classes with a-z
+// ranges and ^ negation, and \ escapes. Works on raw bytes, keys need not
+// be UTF-8
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern {
+        [] => s.is_empty(),
+        [b'*', rest @ ..] => {
+            let rest = &rest[rest.iter().take_while(|&&c| c == b'*').count()..];
+            (0..=s.len()).any(|i| glob_match(rest, &s[i..]))
+        }
+        [b'?', rest @ ..] => !s.is_empty() && glob_match(rest, &s[1..]),
+        [b'[', class @ ..] => match s.split_first() {
+            Some((&c, s)) => {
+                let (matched, rest) = match_class(class, c);
+                matched && glob_match(rest, s)
+            }
+            None => false,
+        },
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => {
+            s.first() == Some(c) && glob_match(rest, &s[1..])
+        }
+    }
+}
+
+// Matches c against a [...] class, pattern starting just after the [.
+// Returns whether it matched and the pattern after the closing ]
+fn match_class(pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let (negate, mut p) = match pattern {
+        [b'^', rest @ ..] => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut matched = false;
+    loop {
+        match p {
+            // an unterminated class ends with the pattern
+            [] => break,
+            [b']', rest @ ..] => {
+                p = rest;
+                break;
+            }
+            [b'\\', x, rest @ ..] => {
+                matched |= *x == c;
+                p = rest;
+            }
+            [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                matched |= *lo <= c && c <= *hi;
+                p = rest;
+            }
+            [x, rest @ ..] => {
+                matched |= *x == c;
+                p = rest;
+            }
+        }
+    }
+    (matched != negate, p)
+}
+
+const ERR_NOT_INTEGER: &str = "ERR value is not an integer or out of range";
+const ERR_OVERFLOW: &str = "ERR increment or decrement would overflow";
+const ERR_INVALID_EXPIRE: &str = "ERR invalid expire time";
+const ERR_OFFSET: &str = "ERR offset is out of range";
+const ERR_STRING_SIZE: &str = "ERR string exceeds maximum allowed size (512MB)";
+const ERR_NO_SUCH_KEY: &str = "ERR no such key";
+const ERR_NO_CONNECTION: &str = "ERR not run on a connection";
+const ERR_WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+fn error_reply(e: &str) -> RedisValue {
+    RedisValue::from_value(Value::from_error(e))
+}
+
+const LOLWUT_ART: &str = concat!(
+    " _______\n",
+    "< redis >\n",
+    " -------\n",
+    "   \\   ^__^\n",
+    "    \\  (oo)\\_______\n",
+    "       (__)\\       )\\/\\\n",
+    "           ||----w |\n",
+    "           ||     ||\n",
+    "\n",
+);
+
+// [name, arity, flags, first key, last key, step] as COMMAND replies it
+fn command_reply(spec: &CommandSpec) -> RedisValue {
+    let flags = spec
+        .flags
+        .iter()
+        .map(|&flag| Value::SimpleString(Bytes::from(flag)))
+        .collect();
+    let (first_key, last_key, step) = spec.keys;
+    RedisValue::array(vec![
+        RedisValue::from_value(Value::from_slice(spec.name.to_ascii_lowercase().as_bytes())),
+        RedisValue::from_value(Value::from_integer(spec.arity)),
+        RedisValue::from_values(flags),
+        RedisValue::from_value(Value::from_integer(first_key)),
+        RedisValue::from_value(Value::from_integer(last_key)),
+        RedisValue::from_value(Value::from_integer(step)),
+    ])
+}
+
+// The index range start..=end selects, redis style: negative indices count
+// from the end (-1 is the last), out of range ones are clamped. None when
+// the range is empty
+fn index_range(len: usize, start: i64, end: i64) -> Option<Range<usize>> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    if start > end || len == 0 {
+        return None;
+    }
+    Some(start as usize..end as usize + 1)
+}
+
+// Appends, at least doubling the capacity whenever it runs out, so repeated
+// APPENDs to a key reallocate a logarithmic number of times
+fn append(stored: &mut Vec<u8>, value: &[u8]) {
+    let needed = stored.len() + value.len();
+    if needed > stored.capacity() {
+        let target = needed.max(stored.capacity() * 2);
+        stored.reserve_exact(target - stored.len());
+    }
+    stored.extend_from_slice(value);
+}
+
+// largest string SETRANGE may create, redis' proto-max-bulk-len default
+const MAX_STRING_SIZE: u64 = 512 * 1024 * 1024;
+
+// longest string redis stores with the embstr encoding
+const EMBSTR_SIZE_LIMIT: usize = 44;
+
+// redis keeps a list in a single listpack until it outgrows 8kb
+// (list-max-listpack-size -2), ignoring per-entry overhead here
+const LISTPACK_SIZE_LIMIT: usize = 8 * 1024;
+
+fn list_encoding(list: &VecDeque<Vec<u8>>) -> &'static [u8] {
+    if list.iter().map(|v| v.len()).sum::<usize>() <= LISTPACK_SIZE_LIMIT {
+        b"listpack"
+    } else {
+        b"quicklist"
+    }
+}
+
+// redis keeps a hash in a listpack while it has at most 128 fields and no
+// field or value longer than 64 bytes (hash-max-listpack-entries/-value)
+const HASH_LISTPACK_ENTRIES: usize = 128;
+const HASH_LISTPACK_VALUE: usize = 64;
+
+fn hash_encoding(hash: &HashMap<Vec<u8>, Vec<u8>>) -> &'static [u8] {
+    let small = |v: &Vec<u8>| v.len() <= HASH_LISTPACK_VALUE;
+    if hash.len() <= HASH_LISTPACK_ENTRIES && hash.iter().all(|(f, v)| small(f) && small(v)) {
+        b"listpack"
+    } else {
+        b"hashtable"
+    }
+}
+
+// redis keeps a set of integers as an intset while it has at most 512
+// members, other sets in a listpack while they have at most 128 members
+// none longer than 64 bytes (set-max-intset-entries, set-max-listpack-*)
+const SET_INTSET_ENTRIES: usize = 512;
+const SET_LISTPACK_ENTRIES: usize = 128;
+const SET_LISTPACK_VALUE: usize = 64;
+
+fn set_encoding(set: &HashSet<Vec<u8>>) -> &'static [u8] {
+    let is_int = |m: &Vec<u8>| encoding(m) == b"int";
+    let is_small = |m: &Vec<u8>| m.len() <= SET_LISTPACK_VALUE;
+    if set.len() <= SET_INTSET_ENTRIES && set.iter().all(is_int) {
+        b"intset"
+    } else if set.len() <= SET_LISTPACK_ENTRIES && set.iter().all(is_small) {
+        b"listpack"
+    } else {
+        b"hashtable"
+    }
+}
+
+// the encoding redis would report for a string value
+fn encoding(s: &[u8]) -> &'static [u8] {
+    match parse_int(s) {
+        // only canonical forms ("12", not "012" or "+12") are stored as ints
+        Some(n) if n.to_string().as_bytes() == s => b"int",
+        _ if s.len() <= EMBSTR_SIZE_LIMIT => b"embstr",
+        _ => b"raw",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str;
+    use bytes::BytesMut;
+    use super::super::commands::{parse_command, DEBUG_NOOPS};
+    use super::super::redis_value::{assert_encodes_as, Node};
+    use super::super::clock::MockClock;
+    use std::time::Duration;
+
+    // run a command against the store
+    fn reply(store: &Store, args: &[&str]) -> RedisValue {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        let req = RedisValue::from_slices(&args);
+        store.run_command(parse_command(req.nodes).unwrap())
+    }
+
+    // the values of every leaf of reply, in order, arrays flattened
+    fn leaves(reply: &RedisValue) -> Vec<Vec<u8>> {
+        // read back, as streamed replies come already encoded
+        let mut buf = BytesMut::new();
+        reply.encode(&mut buf);
+        RedisValue::decode_exact(&buf)
+            .unwrap()
+            .nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::Leaf(v) => Some(v.as_slice().to_vec()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // run a command against the store, returns the encoded reply
+    fn run(store: &Store, args: &[&str]) -> String {
+        let mut buf = BytesMut::new();
+        reply(store, args).encode(&mut buf);
+        String::from_utf8(buf.to_vec()).unwrap()
+    }
+
+    // The exact reply of every command in COMMANDS, run in order against
+    // one store: the wire format each command is pinned to
+    fn wire_formats() -> Vec<(&'static [&'static str], &'static str)> {
+        vec![
+            (&["SET", "k", "v"], "+Ok\r\n"),
+            (&["GET", "k"], "$1\r\nv\r\n"),
+            (&["GET", "missing"], "$-1\r\n"),
+            (&["SETNX", "k", "v"], ":0\r\n"),
+            (&["GETSET", "k", "w"], "$1\r\nv\r\n"),
+            (&["MSET", "a", "1", "b", "2"], "+Ok\r\n"),
+            (&["MGET", "a", "missing"], "*2\r\n$1\r\n1\r\n$-1\r\n"),
+            (&["STRLEN", "k"], ":1\r\n"),
+            (&["APPEND", "k", "xyz"], ":4\r\n"),
+            (&["GETRANGE", "k", "1", "2"], "$2\r\nxy\r\n"),
+            (&["SUBSTR", "k", "0", "0"], "$1\r\nw\r\n"),
+            (&["SETRANGE", "k", "0", "W"], ":4\r\n"),
+            (&["INCR", "a"], ":2\r\n"),
+            (&["DECR", "a"], ":1\r\n"),
+            (&["INCRBY", "a", "10"], ":11\r\n"),
+            (&["DECRBY", "a", "5"], ":6\r\n"),
+            (&["EXISTS", "a", "b", "missing"], ":2\r\n"),
+            (&["TYPE", "k"], "+string\r\n"),
+            (&["OBJECT", "ENCODING", "a"], "$3\r\nint\r\n"),
+            (&["RENAME", "b", "c"], "+Ok\r\n"),
+            (&["RENAMENX", "c", "a"], ":0\r\n"),
+            (&["RPUSH", "l", "x", "y"], ":2\r\n"),
+            (&["LPUSH", "l", "w"], ":3\r\n"),
+            (&["LRANGE", "l", "0", "-1"], "*3\r\n$1\r\nw\r\n$1\r\nx\r\n$1\r\ny\r\n"),
+            (&["LPOP", "l"], "$1\r\nw\r\n"),
+            (&["RPOP", "l"], "$1\r\ny\r\n"),
+            (&["RPOP", "missing"], "$-1\r\n"),
+            (&["RPUSH", "l", "a", "b"], ":3\r\n"),
+            (&["LPOP", "l", "2"], "*2\r\n$1\r\nx\r\n$1\r\na\r\n"),
+            (&["RPOP", "l", "0"], "*0\r\n"),
+            (&["LPOP", "missing", "1"], "*-1\r\n"),
+            (&["HSET", "h", "f", "1", "g", "2"], ":2\r\n"),
+            (&["HGET", "h", "f"], "$1\r\n1\r\n"),
+            (&["HGET", "h", "missing"], "$-1\r\n"),
+            (&["HDEL", "h", "g", "missing"], ":1\r\n"),
+            // a single field, hash order is arbitrary
+            (&["HGETALL", "h"], "*2\r\n$1\r\nf\r\n$1\r\n1\r\n"),
+            (&["HKEYS", "h"], "*1\r\n$1\r\nf\r\n"),
+            (&["HVALS", "h"], "*1\r\n$1\r\n1\r\n"),
+            (&["DEL", "h"], ":1\r\n"),
+            (&["SADD", "set", "m", "n", "m"], ":2\r\n"),
+            (&["SREM", "set", "n", "missing"], ":1\r\n"),
+            (&["SISMEMBER", "set", "m"], ":1\r\n"),
+            (&["SMEMBERS", "set"], "*1\r\n$1\r\nm\r\n"),
+            (&["SCARD", "set"], ":1\r\n"),
+            (&["SINTER", "set", "set"], "*1\r\n$1\r\nm\r\n"),
+            (&["SREM", "set", "m"], ":1\r\n"),
+            (&["EXPIRE", "k", "100"], ":1\r\n"),
+            (&["TTL", "k"], ":100\r\n"),
+            (&["PERSIST", "k"], ":1\r\n"),
+            (&["GETDEL", "c"], "$1\r\n2\r\n"),
+            (&["DEL", "l", "missing"], ":1\r\n"),
+            (&["DBSIZE"], ":2\r\n"),
+            (&["WAIT", "1", "0"], ":0\r\n"),
+            (&["KEYS", "k*"], "*1\r\n$1\r\nk\r\n"),
+            (&["SCAN", "0"], "*2\r\n$1\r\n0\r\n*2\r\n$1\r\na\r\n$1\r\nk\r\n"),
+            (
+                &["MEMORY", "STATS"],
+                "*4\r\n$10\r\nkeys.count\r\n:2\r\n$19\r\nlargest.value.bytes\r\n:4\r\n",
+            ),
+            (&["INFO", "nosuchsection"], "$0\r\n\r\n"),
+            (&["PING"], "+PONG\r\n"),
+            (&["ECHO", "hi"], "$2\r\nhi\r\n"),
+            (
+                &["COMMAND", "INFO", "get"],
+                "*1\r\n*6\r\n$3\r\nget\r\n:2\r\n*1\r\n+readonly\r\n:1\r\n:1\r\n:1\r\n",
+            ),
+            (&["LATENCY", "LATEST"], "*0\r\n"),
+            (&["DEBUG", "CHANGE-REPL-ID"], "+Ok\r\n"),
+            (&["DEBUG", "SET-ACTIVE-EXPIRE", "1"], "+Ok\r\n"),
+            (&["FLUSHDB"], "+Ok\r\n"),
+        ]
+    }
+
+    // commands whose reply can't be pinned here, with where it's checked:
+    // LOLWUT includes the crate version (test_lolwut), MONITOR and CLIENT
+    // are answered by the service (test_monitor, test_client_id)
+    const UNPINNED: &[&str] = &["LOLWUT", "MONITOR", "CLIENT"];
+
+    #[test]
+    fn test_wire_formats() {
+        let (_, store) = mock_store();
+        for (args, expected) in wire_formats() {
+            assert_encodes_as(&reply(&store, args), expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_every_command_pinned() {
+        let pinned = wire_formats();
+        for spec in COMMANDS {
+            assert!(
+                UNPINNED.contains(&spec.name)
+                    || pinned.iter().any(|(args, _)| args[0] == spec.name),
+                "no wire format pinned for {}, add it to wire_formats",
+                spec.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_reply_types() {
+        let store = Store::new();
+        run(&store, &["SET", "n", "1"]);
+        run(&store, &["SET", "s", "foo"]);
+
+        // first byte of the reply: + simple, - error, : integer, $ bulk, * array
+        let matrix: Vec<(&[&str], char)> = vec![
+            (&["SET", "k", "v"], '+'),
+            (&["MSET", "k", "v", "l", "w"], '+'),
+            (&["SETNX", "k", "v"], ':'),
+            (&["GETSET", "k", "v"], '$'),
+            (&["GET", "s"], '$'),
+            (&["GET", "missing"], '$'),
+            (&["GETDEL", "missing"], '$'),
+            (&["MGET", "s", "missing"], '*'),
+            (&["DEL", "k"], ':'),
+            (&["EXISTS", "s"], ':'),
+            (&["STRLEN", "s"], ':'),
+            (&["GETRANGE", "s", "0", "-1"], '$'),
+            (&["SETRANGE", "s", "0", "x"], ':'),
+            (&["TYPE", "s"], '+'),
+            (&["RPUSH", "list", "a", "b"], ':'),
+            (&["LPUSH", "list", "c"], ':'),
+            (&["LRANGE", "list", "0", "-1"], '*'),
+            (&["LRANGE", "s", "0", "-1"], '-'),
+            (&["RENAMENX", "s", "t"], ':'),
+            (&["RENAME", "t", "s"], '+'),
+            (&["RENAME", "missing", "t"], '-'),
+            (&["APPEND", "s", "bar"], ':'),
+            (&["INCR", "n"], ':'),
+            (&["DECR", "n"], ':'),
+            (&["INCRBY", "n", "2"], ':'),
+            (&["DECRBY", "n", "2"], ':'),
+            (&["INCR", "s"], '-'),
+            (&["OBJECT", "ENCODING", "s"], '$'),
+            (&["INFO"], '$'),
+            (&["MEMORY", "STATS"], '*'),
+            (&["SCAN", "0"], '*'),
+            (&["KEYS", "*"], '*'),
+            (&["DBSIZE"], ':'),
+            (&["PING"], '+'),
+            (&["PING", "hi"], '$'),
+            (&["LOLWUT"], '$'),
+            (&["ECHO", "hi"], '$'),
+            (&["COMMAND", "INFO", "get"], '*'),
+            (&["LATENCY", "LATEST"], '*'),
+            (&["LATENCY", "RESET"], ':'),
+            (&["DEBUG", "CHANGE-REPL-ID"], '+'),
+        ];
+
+        for (args, reply_type) in matrix {
+            let reply = run(&store, args);
+            assert_eq!(reply.chars().next(), Some(reply_type), "{:?}: {:?}", args, reply);
+        }
+    }
+
+    #[test]
+    fn test_update_atomic_increment() {
+        use std::sync::Arc;
+        use std::thread;
+
+        fn incr(value: &mut Option<Vec<u8>>) -> Option<i64> {
+            let n = match value {
+                Some(v) => parse_int(v)? + 1,
+                None => 1,
+            };
+            *value = Some(n.to_string().into_bytes());
+            Some(n)
+        }
+
+        let store = Arc::new(Store::new());
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        store.update(b"counter", incr).unwrap().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // the first increment created the key
+        assert_eq!(run(&store, &["GET", "counter"]), "$4\r\n8000\r\n");
+
+        run(&store, &["SET", "s", "abc"]);
+        assert_eq!(store.update(b"s", incr), Ok(None));
+        assert_eq!(run(&store, &["GET", "s"]), "$3\r\nabc\r\n");
+        // wrong type is told apart from missing, and f isn't run
+        run(&store, &["RPUSH", "l", "x"]);
+        assert_eq!(store.update(b"l", |_| panic!("run on a list")), Err::<(), _>(WrongType));
+        // setting the value to None deletes the key
+        assert_eq!(store.update(b"s", |value| value.take()), Ok(Some(b"abc".to_vec())));
+        assert_eq!(run(&store, &["EXISTS", "s"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_update_keeps_expiry() {
+        let (_, store) = mock_store();
+        run(&store, &["SET", "k", "1", "EX", "100"]);
+        run(&store, &["INCR", "k"]);
+        run(&store, &["APPEND", "k", "0"]);
+        run(&store, &["SETRANGE", "k", "0", "3"]);
+        assert_eq!(run(&store, &["GET", "k"]), "$2\r\n30\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]), ":100\r\n");
+    }
+
+    #[test]
+    fn test_strlen() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["STRLEN", "k"]), ":0\r\n");
+        run(&store, &["SET", "k", "foo\r\nbar\0"]);
+        assert_eq!(run(&store, &["STRLEN", "k"]), ":9\r\n");
+    }
+
+    #[test]
+    fn test_append() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["APPEND", "k", "hello"]), ":5\r\n");
+        assert_eq!(run(&store, &["APPEND", "k", " world"]), ":11\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$11\r\nhello world\r\n");
+    }
+
+    #[test]
+    fn test_append_amortized() {
+        let store = Store::new();
+        run(&store, &["SET", "k", "x"]);
+
+        let mut reallocations = 0;
+        let mut capacity = store.update(b"k", |v| v.as_ref().unwrap().capacity()).unwrap();
+        for _ in 0..1000 {
+            run(&store, &["APPEND", "k", "abc"]);
+            let new_capacity = store.update(b"k", |v| v.as_ref().unwrap().capacity()).unwrap();
+            if new_capacity != capacity {
+                reallocations += 1;
+                capacity = new_capacity;
+            }
+        }
+        assert_eq!(store.update(b"k", |v| v.as_ref().unwrap().len()), Ok(3001));
+        // doubling from 1 byte reaches 3001 in 12 steps
+        assert!(reallocations <= 12, "{} reallocations", reallocations);
+    }
+
+    #[test]
+    fn test_index_range() {
+        assert_eq!(index_range(10, 0, -1), Some(0..10));
+        assert_eq!(index_range(10, 2, 4), Some(2..5));
+        assert_eq!(index_range(10, -3, -1), Some(7..10));
+        assert_eq!(index_range(10, -100, 100), Some(0..10));
+        assert_eq!(index_range(10, 5, 2), None);
+        assert_eq!(index_range(10, 10, 20), None);
+        assert_eq!(index_range(10, 0, -11), None);
+        assert_eq!(index_range(0, 0, -1), None);
+        assert_eq!(index_range(10, i64::MIN, i64::MAX), Some(0..10));
+    }
+
+    #[test]
+    fn test_getrange() {
+        let store = Store::new();
+        run(&store, &["SET", "s", "This is a string"]);
+
+        assert_eq!(run(&store, &["GETRANGE", "s", "0", "3"]), "$4\r\nThis\r\n");
+        assert_eq!(run(&store, &["GETRANGE", "s", "-3", "-1"]), "$3\r\ning\r\n");
+        assert_eq!(run(&store, &["GETRANGE", "s", "0", "-1"]), "$16\r\nThis is a string\r\n");
+        assert_eq!(run(&store, &["GETRANGE", "s", "10", "100"]), "$6\r\nstring\r\n");
+        assert_eq!(run(&store, &["GETRANGE", "s", "5", "3"]), "$0\r\n\r\n");
+        assert_eq!(run(&store, &["GETRANGE", "s", "-1", "-5"]), "$0\r\n\r\n");
+        assert_eq!(run(&store, &["SUBSTR", "s", "5", "6"]), "$2\r\nis\r\n");
+        assert_eq!(run(&store, &["GETRANGE", "missing", "0", "-1"]), "$0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_setrange() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["SETRANGE", "k", "0", "hello"]), ":5\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$5\r\nhello\r\n");
+        assert_eq!(run(&store, &["SETRANGE", "k", "1", "EL"]), ":5\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$5\r\nhELlo\r\n");
+        // past the end, padded with NUL bytes
+        assert_eq!(run(&store, &["SETRANGE", "k", "8", "!"]), ":9\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$9\r\nhELlo\0\0\0!\r\n");
+        assert_eq!(run(&store, &["SETRANGE", "pad", "3", "x"]), ":4\r\n");
+        assert_eq!(run(&store, &["GET", "pad"]), "$4\r\n\0\0\0x\r\n");
+
+        assert_eq!(run(&store, &["SETRANGE", "k", "100", ""]), ":9\r\n");
+        assert_eq!(run(&store, &["SETRANGE", "missing", "5", ""]), ":0\r\n");
+        assert_eq!(run(&store, &["EXISTS", "missing"]), ":0\r\n");
+        assert_eq!(
+            run(&store, &["SETRANGE", "k", "-1", "x"]),
+            "-ERR offset is out of range\r\n"
+        );
+        assert_eq!(
+            run(&store, &["SETRANGE", "k", "536870912", "x"]),
+            "-ERR string exceeds maximum allowed size (512MB)\r\n"
+        );
+    }
+
+    #[test]
+    fn test_incr_decr() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["INCR", "n"]), ":1\r\n");
+        assert_eq!(run(&store, &["INCR", "n"]), ":2\r\n");
+        assert_eq!(run(&store, &["DECR", "n"]), ":1\r\n");
+        assert_eq!(run(&store, &["GET", "n"]), "$1\r\n1\r\n");
+        assert_eq!(run(&store, &["DECR", "m"]), ":-1\r\n");
+
+        run(&store, &["SET", "s", "foo"]);
+        assert_eq!(
+            run(&store, &["INCR", "s"]),
+            "-ERR value is not an integer or out of range\r\n"
+        );
+        assert_eq!(run(&store, &["GET", "s"]), "$3\r\nfoo\r\n");
+    }
+
+    #[test]
+    fn test_incrby_decrby() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["INCRBY", "n", "10"]), ":10\r\n");
+        assert_eq!(run(&store, &["INCRBY", "n", "-3"]), ":7\r\n");
+        assert_eq!(run(&store, &["DECRBY", "n", "10"]), ":-3\r\n");
+        assert_eq!(run(&store, &["GET", "n"]), "$2\r\n-3\r\n");
+
+        let max = i64::MAX.to_string();
+        let min = i64::MIN.to_string();
+        run(&store, &["SET", "n", "1"]);
+        assert_eq!(
+            run(&store, &["INCRBY", "n", &max]),
+            "-ERR increment or decrement would overflow\r\n"
+        );
+        assert_eq!(run(&store, &["GET", "n"]), "$1\r\n1\r\n");
+        assert_eq!(
+            run(&store, &["DECRBY", "n", &min]),
+            "-ERR increment or decrement would overflow\r\n"
+        );
+        assert_eq!(run(&store, &["DECRBY", "n", &max]), format!(":{}\r\n", 1 - i64::MAX));
+        assert_eq!(
+            run(&store, &["DECRBY", "n", "3"]),
+            "-ERR increment or decrement would overflow\r\n"
+        );
+    }
+
+    #[test]
+    fn test_incr_decr_overflow() {
+        let store = Store::new();
+        let max = i64::MAX.to_string();
+        let min = i64::MIN.to_string();
+
+        run(&store, &["SET", "max", &max]);
+        assert_eq!(
+            run(&store, &["INCR", "max"]),
+            "-ERR increment or decrement would overflow\r\n"
+        );
+        assert_eq!(run(&store, &["GET", "max"]), format!("$19\r\n{}\r\n", max));
+        assert_eq!(run(&store, &["DECR", "max"]), format!(":{}\r\n", i64::MAX - 1));
+
+        run(&store, &["SET", "min", &min]);
+        assert_eq!(
+            run(&store, &["DECR", "min"]),
+            "-ERR increment or decrement would overflow\r\n"
+        );
+
+        run(&store, &["SET", "big", "9223372036854775808"]);
+        assert_eq!(
+            run(&store, &["INCR", "big"]),
+            "-ERR value is not an integer or out of range\r\n"
+        );
+    }
+
+    #[test]
+    fn test_largest_value() {
+        let store = Store::new();
+        assert_eq!(store.largest_value(), 0);
+
+        run(&store, &["SET", "small", "x"]);
+        run(&store, &["SET", "large", &"x".repeat(100)]);
+        run(&store, &["SET", "medium", &"x".repeat(10)]);
+        assert_eq!(store.largest_value(), 100);
+        assert_eq!(
+            run(&store, &["INFO", "memory"]),
+            "$35\r\n# Memory\r\nlargest_value_bytes:100\r\n\r\n"
+        );
+        assert_eq!(
+            run(&store, &["MEMORY", "STATS"]),
+            "*4\r\n$10\r\nkeys.count\r\n:3\r\n$19\r\nlargest.value.bytes\r\n:100\r\n"
+        );
+
+        run(&store, &["DEL", "large"]);
+        assert_eq!(store.largest_value(), 10);
+        assert!(run(&store, &["INFO"]).contains("largest_value_bytes:10\r\n"));
+
+        run(&store, &["SET", "medium", "xy"]);
+        assert_eq!(store.largest_value(), 2);
+        assert_eq!(run(&store, &["INFO", "keyspace"]), "$0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_scan_reply() {
+        let store = Store::new();
+        assert_eq!(run(&store, &["SCAN", "0"]), "*2\r\n$1\r\n0\r\n*0\r\n");
+
+        run(&store, &["SET", "a", "1"]);
+        assert_eq!(
+            run(&store, &["SCAN", "0", "COUNT", "5"]),
+            "*2\r\n$1\r\n0\r\n*1\r\n$1\r\na\r\n"
+        );
+    }
+
+    #[test]
+    fn test_scan_batch() {
+        let keys: Vec<Item> = (0..100).map(|i| format!("k{}", i).into_bytes()).collect();
+        let mut sorted: Vec<(u64, &Item)> = keys.iter().map(|k| (scan_hash(k), k)).collect();
+        sorted.sort_unstable();
+
+        // the same batches a full sort would give
+        let mut cursor = 0;
+        let mut start = 0;
+        loop {
+            let (next, batch) = scan_batch(keys.iter(), cursor, 7);
+            let end = (start + 7).min(sorted.len());
+            let expected: Vec<&Item> = sorted[start..end].iter().map(|&(_, k)| k).collect();
+            assert_eq!(batch, expected);
+            start = end;
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+            assert_eq!(cursor, sorted[end - 1].0 + 1);
+        }
+        assert_eq!(start, keys.len());
+    }
+
+    #[test]
+    fn test_scan_guarantee() {
+        use std::collections::HashSet;
+
+        let store = Store::new();
+        for i in 0..200 {
+            run(&store, &["SET", &format!("stable:{}", i), "x"]);
+            run(&store, &["SET", &format!("doomed:{}", i), "x"]);
+        }
+
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            let (next, keys) = store.scan(cursor, 7);
+            seen.extend(keys);
+
+            // churn the map between calls, forcing it to grow and rehash
+            for i in 0..10 {
+                run(&store, &["DEL", &format!("doomed:{}", calls * 10 + i)]);
+                run(&store, &["SET", &format!("new:{}:{}", calls, i), "x"]);
+            }
+            calls += 1;
+
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        for i in 0..200 {
+            assert!(seen.contains(format!("stable:{}", i).as_bytes()));
+        }
+        assert!(calls < 200);
+    }
+
+    #[test]
+    fn test_push_lrange() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["RPUSH", "l", "a", "b"]), ":2\r\n");
+        // pushed one at a time, so c ends up ahead of d
+        assert_eq!(run(&store, &["LPUSH", "l", "d", "c"]), ":4\r\n");
+        assert_eq!(
+            run(&store, &["LRANGE", "l", "0", "-1"]),
+            "*4\r\n$1\r\nc\r\n$1\r\nd\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            run(&store, &["LRANGE", "l", "-2", "100"]),
+            "*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(run(&store, &["LRANGE", "l", "1", "1"]), "*1\r\n$1\r\nd\r\n");
+        assert_eq!(run(&store, &["LRANGE", "l", "3", "1"]), "*0\r\n");
+        assert_eq!(run(&store, &["LRANGE", "l", "5", "10"]), "*0\r\n");
+        assert_eq!(run(&store, &["LRANGE", "missing", "0", "-1"]), "*0\r\n");
+
+        assert_eq!(run(&store, &["TYPE", "l"]), "+list\r\n");
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "l"]), "$8\r\nlistpack\r\n");
+        run(&store, &["RPUSH", "l", &"x".repeat(LISTPACK_SIZE_LIMIT)]);
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "l"]), "$9\r\nquicklist\r\n");
+        assert_eq!(store.largest_value(), LISTPACK_SIZE_LIMIT + 4);
+    }
+
+    #[test]
+    fn test_hset_hget_hdel() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["HSET", "h", "a", "1", "b", "2"]), ":2\r\n");
+        // updating an existing field creates nothing
+        assert_eq!(run(&store, &["HSET", "h", "a", "10"]), ":0\r\n");
+        assert_eq!(run(&store, &["HSET", "h", "a", "11", "c", "3"]), ":1\r\n");
+        // a field repeated in one call is created once
+        assert_eq!(run(&store, &["HSET", "h", "d", "4", "d", "5"]), ":1\r\n");
+        assert_eq!(run(&store, &["HGET", "h", "a"]), "$2\r\n11\r\n");
+        assert_eq!(run(&store, &["HGET", "h", "d"]), "$1\r\n5\r\n");
+        assert_eq!(run(&store, &["HGET", "h", "missing"]), "$-1\r\n");
+        assert_eq!(run(&store, &["HGET", "missing", "a"]), "$-1\r\n");
+        assert_eq!(run(&store, &["TYPE", "h"]), "+hash\r\n");
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "h"]), "$8\r\nlistpack\r\n");
+
+        assert_eq!(run(&store, &["HDEL", "h", "a", "missing", "a"]), ":1\r\n");
+        assert_eq!(run(&store, &["HGET", "h", "a"]), "$-1\r\n");
+        assert_eq!(run(&store, &["HDEL", "missing", "a"]), ":0\r\n");
+        // removing the last field deletes the key
+        assert_eq!(run(&store, &["HDEL", "h", "b", "c", "d"]), ":3\r\n");
+        assert_eq!(run(&store, &["EXISTS", "h"]), ":0\r\n");
+
+        run(&store, &["HSET", "big", "f", &"x".repeat(HASH_LISTPACK_VALUE + 1)]);
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "big"]), "$9\r\nhashtable\r\n");
+        assert_eq!(store.largest_value(), HASH_LISTPACK_VALUE + 2);
+    }
+
+    #[test]
+    fn test_hgetall_hkeys_hvals() {
+        let store = Store::new();
+        run(&store, &["HSET", "h", "a", "1", "b", "2", "c", "3"]);
+
+        // map order is arbitrary: check pairing and membership only
+        let all = leaves(&reply(&store, &["HGETALL", "h"]));
+        let mut pairs: Vec<(&[u8], &[u8])> =
+            all.chunks(2).map(|pair| (&pair[0][..], &pair[1][..])).collect();
+        pairs.sort();
+        assert_eq!(pairs, vec![(&b"a"[..], &b"1"[..]), (b"b", b"2"), (b"c", b"3")]);
+
+        let sorted = |args: &[&str]| {
+            let mut items = leaves(&reply(&store, args));
+            items.sort();
+            items
+        };
+        assert_eq!(sorted(&["HKEYS", "h"]), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(sorted(&["HVALS", "h"]), vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+
+        assert_eq!(run(&store, &["HGETALL", "missing"]), "*0\r\n");
+        assert_eq!(run(&store, &["HKEYS", "missing"]), "*0\r\n");
+        assert_eq!(run(&store, &["HVALS", "missing"]), "*0\r\n");
+    }
+
+    #[test]
+    fn test_sets() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["SADD", "s", "a", "b"]), ":2\r\n");
+        // members already in the set aren't counted
+        assert_eq!(run(&store, &["SADD", "s", "a"]), ":0\r\n");
+        assert_eq!(run(&store, &["SADD", "s", "b", "c", "c"]), ":1\r\n");
+        assert_eq!(run(&store, &["SISMEMBER", "s", "c"]), ":1\r\n");
+        assert_eq!(run(&store, &["SISMEMBER", "s", "d"]), ":0\r\n");
+        assert_eq!(run(&store, &["SISMEMBER", "missing", "a"]), ":0\r\n");
+        assert_eq!(run(&store, &["TYPE", "s"]), "+set\r\n");
+
+        // set order is arbitrary
+        let mut members = leaves(&reply(&store, &["SMEMBERS", "s"]));
+        members.sort();
+        assert_eq!(members, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(run(&store, &["SMEMBERS", "missing"]), "*0\r\n");
+
+        assert_eq!(run(&store, &["SREM", "s", "a", "d", "a"]), ":1\r\n");
+        assert_eq!(run(&store, &["SREM", "missing", "a"]), ":0\r\n");
+        // removing the last member deletes the key
+        assert_eq!(run(&store, &["SREM", "s", "b", "c"]), ":2\r\n");
+        assert_eq!(run(&store, &["EXISTS", "s"]), ":0\r\n");
+
+        run(&store, &["SADD", "ints", "1", "2", "-3"]);
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "ints"]), "$6\r\nintset\r\n");
+        run(&store, &["SADD", "ints", "x"]);
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "ints"]), "$8\r\nlistpack\r\n");
+        run(&store, &["SADD", "ints", &"x".repeat(SET_LISTPACK_VALUE + 1)]);
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "ints"]), "$9\r\nhashtable\r\n");
+    }
+
+    #[test]
+    fn test_scard_sinter() {
+        let store = Store::new();
+        run(&store, &["SADD", "a", "1", "2", "3", "4", "5"]);
+        run(&store, &["SADD", "b", "2", "3", "4", "6"]);
+        run(&store, &["SADD", "c", "3", "4", "7"]);
+        run(&store, &["SET", "s", "x"]);
+
+        assert_eq!(run(&store, &["SCARD", "a"]), ":5\r\n");
+        assert_eq!(run(&store, &["SCARD", "missing"]), ":0\r\n");
+
+        let mut members = leaves(&reply(&store, &["SINTER", "a", "b", "c"]));
+        members.sort();
+        assert_eq!(members, vec![b"3".to_vec(), b"4".to_vec()]);
+        assert_eq!(run(&store, &["SINTER", "c"]).len(), "*3\r\n".len() + 3 * 7);
+
+        // a missing key short-circuits, even ahead of a wrong-type key
+        assert_eq!(run(&store, &["SINTER", "a", "missing", "b"]), "*0\r\n");
+        assert_eq!(run(&store, &["SINTER", "missing", "s"]), "*0\r\n");
+        assert_eq!(
+            run(&store, &["SINTER", "s", "missing"]),
+            format!("-{}\r\n", ERR_WRONGTYPE)
+        );
+    }
+
+    #[test]
+    fn test_wait_returns_immediately() {
+        let store = Store::new();
+        let start = Instant::now();
+        assert_eq!(run(&store, &["WAIT", "1", "100"]), ":0\r\n");
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(run(&store, &["WAIT", "0", "0"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_pop_count() {
+        let store = Store::new();
+        run(&store, &["RPUSH", "l", "a", "b", "c", "d", "e"]);
+
+        // without a count, a single bulk string
+        assert_eq!(run(&store, &["LPOP", "l"]), "$1\r\na\r\n");
+        // with one, an array even of a single element
+        assert_eq!(run(&store, &["LPOP", "l", "1"]), "*1\r\n$1\r\nb\r\n");
+        assert_eq!(run(&store, &["RPOP", "l", "2"]), "*2\r\n$1\r\ne\r\n$1\r\nd\r\n");
+        assert_eq!(run(&store, &["LPOP", "l", "0"]), "*0\r\n");
+        // more than there are takes them all, and the key with them
+        assert_eq!(run(&store, &["LPOP", "l", "10"]), "*1\r\n$1\r\nc\r\n");
+        assert_eq!(run(&store, &["EXISTS", "l"]), ":0\r\n");
+
+        assert_eq!(run(&store, &["LPOP", "l"]), "$-1\r\n");
+        // with a count, a missing key is a null array rather than a null bulk
+        assert_eq!(run(&store, &["RPOP", "l", "2"]), "*-1\r\n");
+    }
+
+    #[test]
+    fn test_emptied_collections_deleted() {
+        let store = Store::new();
+
+        run(&store, &["RPUSH", "list", "a", "b"]);
+        assert_eq!(run(&store, &["LPOP", "list"]), "$1\r\na\r\n");
+        assert_eq!(run(&store, &["RPOP", "list"]), "$1\r\nb\r\n");
+        assert_eq!(run(&store, &["EXISTS", "list"]), ":0\r\n");
+        assert_eq!(run(&store, &["LPOP", "list"]), "$-1\r\n");
+
+        run(&store, &["HSET", "hash", "f", "v"]);
+        run(&store, &["HDEL", "hash", "f"]);
+        assert_eq!(run(&store, &["EXISTS", "hash"]), ":0\r\n");
+
+        run(&store, &["SADD", "set", "m"]);
+        run(&store, &["SREM", "set", "m"]);
+        assert_eq!(run(&store, &["EXISTS", "set"]), ":0\r\n");
+
+        // an empty string is a value like any other
+        run(&store, &["SET", "string", ""]);
+        assert_eq!(run(&store, &["EXISTS", "string"]), ":1\r\n");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "empty list left at \"l\"")]
+    fn test_empty_collection_left_behind_panics() {
+        let store = Store::new();
+        run(&store, &["RPUSH", "l", "a"]);
+        // a command popping without remove_if_empty
+        store.store.write().unwrap().get_mut(&b"l"[..]).unwrap().value =
+            StoreValue::List(VecDeque::new());
+        run(&store, &["LRANGE", "l", "0", "-1"]);
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let wrongtype = format!("-{}\r\n", ERR_WRONGTYPE);
+        let store = Store::new();
+        run(&store, &["SET", "s", "foo"]);
+        run(&store, &["RPUSH", "l", "a"]);
+
+        let mut string = StoreValue::String(b"foo".to_vec());
+        assert_eq!(string.as_list_mut().err(), Some(WrongType));
+        assert_eq!(string.as_list().err(), Some(WrongType));
+
+        for args in vec![
+            vec!["LPUSH", "s", "x"],
+            vec!["RPUSH", "s", "x"],
+            vec!["LRANGE", "s", "0", "-1"],
+            vec!["LPOP", "s"],
+            vec!["RPOP", "s"],
+            vec!["HSET", "s", "f", "v"],
+            vec!["HGET", "s", "f"],
+            vec!["HDEL", "s", "f"],
+            vec!["HGET", "l", "f"],
+            vec!["HGETALL", "s"],
+            vec!["HKEYS", "s"],
+            vec!["HVALS", "l"],
+            vec!["SADD", "s", "m"],
+            vec!["SREM", "s", "m"],
+            vec!["SMEMBERS", "s"],
+            vec!["SISMEMBER", "l", "m"],
+            vec!["SCARD", "s"],
+            vec!["SINTER", "s"],
+            vec!["GET", "l"],
+            vec!["GETDEL", "l"],
+            vec!["GETSET", "l", "x"],
+            vec!["STRLEN", "l"],
+            vec!["GETRANGE", "l", "0", "-1"],
+            vec!["SETRANGE", "l", "0", "x"],
+            vec!["APPEND", "l", "x"],
+            vec!["INCR", "l"],
+        ] {
+            assert_eq!(run(&store, &args), wrongtype, "{:?}", args);
+        }
+        // failed commands leave both keys untouched
+        assert_eq!(run(&store, &["GET", "s"]), "$3\r\nfoo\r\n");
+        assert_eq!(run(&store, &["LRANGE", "l", "0", "-1"]), "*1\r\n$1\r\na\r\n");
+        // MGET answers nil for a non-string key, like redis
+        assert_eq!(run(&store, &["MGET", "l", "s"]), "*2\r\n$-1\r\n$3\r\nfoo\r\n");
+
+        // SET replaces a value of any type
+        assert_eq!(run(&store, &["SET", "l", "bar"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["GET", "l"]), "$3\r\nbar\r\n");
+        assert_eq!(run(&store, &["TYPE", "l"]), "+string\r\n");
+    }
+
+    #[test]
+    fn test_set_replaces_any_type() {
+        let store = Store::new();
+        run(&store, &["RPUSH", "l", "a", "b"]);
+        run(&store, &["HSET", "h", "f", "v"]);
+        run(&store, &["SADD", "s", "m"]);
+
+        assert_eq!(run(&store, &["SET", "l", "1"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["SET", "h", "2", "XX"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["SET", "s", "3", "EX", "10"]), "+Ok\r\n");
+        for (key, value) in &[("l", "$1\r\n1\r\n"), ("h", "$1\r\n2\r\n"), ("s", "$1\r\n3\r\n")] {
+            assert_eq!(run(&store, &["GET", key]), *value);
+            assert_eq!(run(&store, &["TYPE", key]), "+string\r\n");
+        }
+        // the old elements went with the old value
+        assert_eq!(store.largest_value(), 1);
+    }
+
+    #[test]
+    fn test_type() {
+        let store = Store::new();
+        run(&store, &["SET", "s", "foo"]);
+        run(&store, &["RPUSH", "l", "a"]);
+        run(&store, &["HSET", "h", "f", "v"]);
+        run(&store, &["SADD", "set", "m"]);
+        assert_eq!(run(&store, &["TYPE", "s"]), "+string\r\n");
+        assert_eq!(run(&store, &["TYPE", "l"]), "+list\r\n");
+        assert_eq!(run(&store, &["TYPE", "h"]), "+hash\r\n");
+        assert_eq!(run(&store, &["TYPE", "set"]), "+set\r\n");
+        assert_eq!(run(&store, &["type", "missing"]), "+none\r\n");
+    }
+
+    #[test]
+    fn test_rename() {
+        let store = Store::new();
+        run(&store, &["SET", "a", "1"]);
+        run(&store, &["SET", "b", "2"]);
+
+        assert_eq!(run(&store, &["RENAME", "a", "c"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["GET", "a"]), "$-1\r\n");
+        assert_eq!(run(&store, &["GET", "c"]), "$1\r\n1\r\n");
+        // overwrites the destination
+        assert_eq!(run(&store, &["RENAME", "c", "b"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["GET", "b"]), "$1\r\n1\r\n");
+        assert_eq!(run(&store, &["RENAME", "b", "b"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["GET", "b"]), "$1\r\n1\r\n");
+
+        assert_eq!(run(&store, &["RENAME", "missing", "d"]), "-ERR no such key\r\n");
+        assert_eq!(run(&store, &["RENAMENX", "missing", "d"]), "-ERR no such key\r\n");
+        assert_eq!(run(&store, &["EXISTS", "d"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_renamenx() {
+        let store = Store::new();
+        run(&store, &["SET", "a", "1"]);
+        run(&store, &["SET", "b", "2"]);
+
+        assert_eq!(run(&store, &["RENAMENX", "a", "b"]), ":0\r\n");
+        assert_eq!(run(&store, &["GET", "a"]), "$1\r\n1\r\n");
+        assert_eq!(run(&store, &["GET", "b"]), "$1\r\n2\r\n");
+
+        assert_eq!(run(&store, &["RENAMENX", "a", "c"]), ":1\r\n");
+        assert_eq!(run(&store, &["EXISTS", "a", "c"]), ":1\r\n");
+        assert_eq!(run(&store, &["GET", "c"]), "$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&[u8], &[u8], bool)] = &[
+            (b"*", b"", true),
+            (b"*", b"anything", true),
+            (b"user:*", b"user:1", true),
+            (b"user:*", b"user:", true),
+            (b"user:*", b"session:1", false),
+            (b"*:1", b"user:1", true),
+            (b"h?llo", b"hello", true),
+            (b"h?llo", b"hllo", false),
+            (b"h*llo", b"heeeello", true),
+            (b"h**o", b"hello", true),
+            (b"h[ae]llo", b"hallo", true),
+            (b"h[ae]llo", b"hillo", false),
+            (b"h[^e]llo", b"hallo", true),
+            (b"h[^e]llo", b"hello", false),
+            (b"h[a-c]llo", b"hbllo", true),
+            (b"h[a-c]llo", b"hdllo", false),
+            (b"h[c-a]llo", b"hbllo", true),
+            (b"h[^a-c]llo", b"hdllo", true),
+            (b"[a-]", b"-", true),
+            (b"h\\*llo", b"h*llo", true),
+            (b"h\\*llo", b"hello", false),
+            (b"[\\]]", b"]", true),
+            (b"\xff*", b"\xff\x00\x80", true),
+            (b"[\x80-\xff]", b"\x90", true),
+            (b"[\x80-\xff]", b"a", false),
+        ];
+        for &(pattern, s, expected) in cases {
+            assert_eq!(glob_match(pattern, s), expected, "{:?} {:?}", pattern, s);
+        }
+    }
+
+    #[test]
+    fn test_keys() {
+        let store = Store::new();
+        for key in &["user:1", "user:2", "user:10", "session:1"] {
+            run(&store, &["SET", key, "x"]);
+        }
+
+        let mut keys = store.keys(b"user:?");
+        keys.sort();
+        assert_eq!(keys, vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+        assert_eq!(store.keys(b"*").len(), 4);
+        assert_eq!(store.keys(b"*:1").len(), 2);
+        assert_eq!(store.keys(b"user:[^1]").len(), 1);
+        assert!(store.keys(b"nope*").is_empty());
+
+        assert_eq!(run(&store, &["KEYS", "session:*"]), "*1\r\n$9\r\nsession:1\r\n");
+        assert_eq!(run(&store, &["KEYS", "nope"]), "*0\r\n");
+    }
+
+    fn mock_store() -> (Arc<MockClock>, Store) {
+        let clock = Arc::new(MockClock::new());
+        let store = Store::with_clock(clock.clone());
+        (clock, store)
+    }
+
+    #[test]
+    fn test_expire_ttl() {
+        let (clock, store) = mock_store();
+        run(&store, &["SET", "k", "v"]);
+
+        assert_eq!(run(&store, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(run(&store, &["TTL", "missing"]), ":-2\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "missing", "10"]), ":0\r\n");
+        assert_eq!(run(&store, &["EXPIRE", "k", "10"]), ":1\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]), ":10\r\n");
+
+        clock.advance(Duration::from_millis(3400));
+        assert_eq!(run(&store, &["TTL", "k"]), ":7\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$1\r\nv\r\n");
+
+        clock.advance(Duration::from_millis(6600));
+        assert_eq!(run(&store, &["GET", "k"]), "$-1\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]), ":-2\r\n");
+        // removed by the GET, not just hidden
+        assert!(store.store.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_persist() {
+        let (clock, store) = mock_store();
+        run(&store, &["SET", "k", "v"]);
+
+        assert_eq!(run(&store, &["PERSIST", "k"]), ":0\r\n");
+        assert_eq!(run(&store, &["PERSIST", "missing"]), ":0\r\n");
+        run(&store, &["EXPIRE", "k", "10"]);
+        assert_eq!(run(&store, &["PERSIST", "k"]), ":1\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(run(&store, &["PERSIST", "k"]), ":0\r\n");
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(run(&store, &["GET", "k"]), "$1\r\nv\r\n");
+    }
+
+    #[test]
+    fn test_len_after_expiry() {
+        let (clock, store) = mock_store();
+        assert!(store.is_empty());
+        run(&store, &["SET", "a", "1", "EX", "1"]);
+        run(&store, &["SET", "b", "2"]);
+        assert_eq!(store.len(), 2);
+
+        clock.advance(Duration::from_secs(1));
+        // still counted until touched
+        assert_eq!(store.len(), 2);
+        run(&store, &["GET", "a"]);
+        assert_eq!(store.len(), 1);
+        assert_eq!(run(&store, &["DBSIZE"]), ":1\r\n");
+
+        run(&store, &["DEL", "b"]);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_sweep_expired() {
+        let (clock, store) = mock_store();
+        for i in 0..100 {
+            run(&store, &["SET", &format!("k{}", i), "v", "EX", "1"]);
+            run(&store, &["SET", &format!("live{}", i), "v"]);
+        }
+
+        // nothing is due yet, and keys without an expiry are never looked at
+        assert_eq!(store.sweep_expired(10), (0, 0));
+        clock.advance(Duration::from_secs(1));
+        for _ in 0..10 {
+            assert_eq!(store.sweep_expired(10), (10, 10));
+        }
+        assert_eq!(store.sweep_expired(10), (0, 0));
+        assert_eq!(store.len(), 100);
+        assert!(store.keys(b"*").iter().all(|k| k.starts_with(b"live")));
+    }
+
+    #[test]
+    fn test_sweep_follows_expiry_changes() {
+        let (clock, store) = mock_store();
+        run(&store, &["SET", "persisted", "v", "EX", "1"]);
+        run(&store, &["PERSIST", "persisted"]);
+        run(&store, &["SET", "later", "v", "EX", "1"]);
+        run(&store, &["EXPIRE", "later", "2"]);
+        run(&store, &["SET", "deleted", "v", "EX", "1"]);
+        run(&store, &["DEL", "deleted"]);
+        run(&store, &["SET", "renamed", "v", "EX", "1"]);
+        run(&store, &["RENAME", "renamed", "moved"]);
+
+        clock.advance(Duration::from_secs(1));
+        // the stale entries are taken but their keys kept, the renamed key
+        // is found under its new name
+        assert_eq!(store.sweep_expired(10), (5, 1));
+        assert_eq!(store.store.read().unwrap().len(), 2);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(store.sweep_expired(10), (1, 1));
+        assert_eq!(run(&store, &["KEYS", "*"]), "*1\r\n$9\r\npersisted\r\n");
+        assert!(store.expiries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expiry_sweep_thread() {
+        let store = Store::with_expiry_sweep(Duration::from_millis(5));
+        run(&store, &["SET", "k", "v", "PX", "1"]);
+        assert_eq!(store.len(), 1);
+
+        let mut waited = 0;
+        while !store.is_empty() && waited < 2000 {
+            thread::sleep(Duration::from_millis(10));
+            waited += 10;
+        }
+        assert!(store.is_empty(), "not swept after {}ms", waited);
+        // the sweeper only holds a weak reference
+        assert_eq!(Arc::weak_count(&store), 1);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let fill = |commands: &[&[&str]]| {
+            let store = Store::new();
+            for args in commands {
+                run(&store, args);
+            }
+            store
+        };
+        let a = fill(&[
+            &["SET", "s", "v"],
+            &["RPUSH", "l", "1", "2"],
+            &["HSET", "h", "f", "1", "g", "2"],
+            &["SADD", "set", "x", "y", "z"],
+        ]);
+        let b = fill(&[
+            &["SADD", "set", "z"],
+            &["HSET", "h", "g", "2"],
+            &["SADD", "set", "y", "x"],
+            &["LPUSH", "l", "2", "1"],
+            &["HSET", "h", "f", "1"],
+            &["SET", "s", "v", "EX", "100"],
+        ]);
+        assert_eq!(a.checksum(), b.checksum());
+        assert_ne!(a.checksum(), Store::new().checksum());
+        assert_eq!(run(&a, &["DEBUG", "DIGEST"]), format!("+{:016x}\r\n", a.checksum()));
+        assert_eq!(run(&Store::new(), &["DEBUG", "DIGEST"]), "+0000000000000000\r\n");
+
+        // one differing value, or the same one under another key or type
+        run(&b, &["HSET", "h", "g", "3"]);
+        assert_ne!(a.checksum(), b.checksum());
+        run(&b, &["HSET", "h", "g", "2"]);
+        assert_eq!(a.checksum(), b.checksum());
+        run(&b, &["RENAME", "s", "t"]);
+        assert_ne!(a.checksum(), b.checksum());
+        run(&b, &["RENAME", "t", "s"]);
+        run(&b, &["RPUSH", "l", "3"]);
+        run(&b, &["RPOP", "l"]);
+        assert_eq!(a.checksum(), b.checksum());
+        run(&b, &["DEL", "l"]);
+        run(&b, &["SADD", "l", "1", "2"]);
+        assert_ne!(a.checksum(), b.checksum());
+    }
+
+    #[test]
+    fn test_set_active_expire() {
+        let store = Store::with_expiry_sweep(Duration::from_millis(5));
+        assert_eq!(run(&store, &["DEBUG", "SET-ACTIVE-EXPIRE", "0"]), "+Ok\r\n");
+        run(&store, &["SET", "k", "v", "PX", "1"]);
+        thread::sleep(Duration::from_millis(50));
+        // expired but unread, so still in the map
+        assert!(store.store.read().unwrap().contains_key(&b"k"[..]));
+        assert_eq!(run(&store, &["GET", "k"]), "$-1\r\n");
+        assert!(store.is_empty());
+
+        run(&store, &["debug", "set-active-expire", "1"]);
+        run(&store, &["SET", "k", "v", "PX", "1"]);
+        let mut waited = 0;
+        while !store.is_empty() && waited < 2000 {
+            thread::sleep(Duration::from_millis(10));
+            waited += 10;
+        }
+        assert!(store.is_empty(), "not swept after {}ms", waited);
+    }
+
+    #[test]
+    fn test_set_with_expire() {
+        let (clock, store) = mock_store();
+
+        assert_eq!(run(&store, &["SET", "k", "v", "EX", "5"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]), ":5\r\n");
+        // a plain SET clears the expiry
+        run(&store, &["SET", "k", "v"]);
+        assert_eq!(run(&store, &["TTL", "k"]), ":-1\r\n");
+
+        assert_eq!(run(&store, &["SET", "k", "v", "PX", "1500"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["TTL", "k"]), ":2\r\n");
+        // in place updates keep it
+        run(&store, &["APPEND", "k", "w"]);
+        assert_eq!(run(&store, &["TTL", "k"]), ":2\r\n");
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(run(&store, &["EXISTS", "k"]), ":0\r\n");
+        assert_eq!(run(&store, &["SET", "k", "v", "XX"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn test_expired_keys_are_missing() {
+        let (clock, store) = mock_store();
+        for key in &["a", "b", "c", "d"] {
+            run(&store, &["SET", key, "old", "EX", "1"]);
+        }
+        run(&store, &["SET", "live", "1"]);
+        run(&store, &["SET", "moved", "1", "EX", "5"]);
+        run(&store, &["RENAME", "moved", "renamed"]);
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(run(&store, &["SETNX", "a", "new"]), ":1\r\n");
+        assert_eq!(run(&store, &["SET", "b", "new", "NX"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["INCR", "c"]), ":1\r\n");
+        assert_eq!(run(&store, &["RENAMENX", "live", "d"]), ":1\r\n");
+        assert_eq!(run(&store, &["TTL", "a"]), ":-1\r\n");
+        // RENAME carries the expiry along
+        assert_eq!(run(&store, &["TTL", "renamed"]), ":4\r\n");
+
+        run(&store, &["SET", "e", "x", "EX", "1"]);
+        clock.advance(Duration::from_secs(1));
+        assert!(store.keys(b"*").iter().all(|k| k != b"e"));
+        assert_eq!(store.scan(0, 100).1.len(), 5);
+        assert_eq!(run(&store, &["MGET", "e", "a"]), "*2\r\n$-1\r\n$3\r\nnew\r\n");
+    }
+
+    #[test]
+    fn test_reads_see_expiry() {
+        let (clock, store) = mock_store();
+        run(&store, &["SET", "s", "v", "EX", "1"]);
+        run(&store, &["RPUSH", "l", "a"]);
+        run(&store, &["HSET", "h", "f", "v"]);
+        run(&store, &["SADD", "set", "m"]);
+        for key in &["l", "h", "set"] {
+            run(&store, &["EXPIRE", key, "1"]);
+        }
+        clock.advance(Duration::from_secs(1));
+
+        // each is the first command to touch the key since it expired
+        assert_eq!(run(&store, &["EXISTS", "s"]), ":0\r\n");
+        assert_eq!(run(&store, &["TYPE", "l"]), "+none\r\n");
+        assert_eq!(run(&store, &["HGETALL", "h"]), "*0\r\n");
+        assert_eq!(run(&store, &["SCARD", "set"]), ":0\r\n");
+        assert_eq!(run(&store, &["DBSIZE"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_set_nx_xx() {
+        let store = Store::new();
+        assert_eq!(run(&store, &["SET", "k", "1", "XX"]), "$-1\r\n");
+        assert_eq!(run(&store, &["EXISTS", "k"]), ":0\r\n");
+        assert_eq!(run(&store, &["SET", "k", "1", "NX"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["set", "k", "2", "nx"]), "$-1\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$1\r\n1\r\n");
+        assert_eq!(run(&store, &["SET", "k", "3", "XX"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$1\r\n3\r\n");
+    }
+
+    #[test]
+    fn test_ping() {
+        let store = Store::new();
+        assert_eq!(run(&store, &["PING"]), "+PONG\r\n");
+        assert_eq!(run(&store, &["ping", "hello world"]), "$11\r\nhello world\r\n");
+        assert_eq!(run(&store, &["PING", ""]), "$0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_echo() {
+        let store = Store::new();
+        let req = RedisValue::from_slices(&[b"ECHO", b"a\0b\r\n\0"]);
+        let response = store.run_command(parse_command(req.nodes).unwrap());
+
+        let mut buf = BytesMut::new();
+        response.encode(&mut buf);
+        assert_eq!(&buf[..], b"$6\r\na\0b\r\n\0\r\n");
+        assert_eq!(run(&store, &["echo", ""]), "$0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_lolwut() {
+        let store = Store::new();
+        let reply = run(&store, &["LOLWUT"]);
+        assert!(reply.starts_with('$') && reply.len() > "$0\r\n\r\n".len());
+        assert!(reply.contains(env!("CARGO_PKG_VERSION")));
+        assert_eq!(run(&store, &["lolwut", "version", "5"]), reply);
+    }
+
+    #[test]
+    fn test_flushdb() {
+        let store = Store::new();
+        assert_eq!(run(&store, &["DBSIZE"]), ":0\r\n");
+        run(&store, &["SET", "a", "1"]);
+        run(&store, &["SET", "b", "2"]);
+        run(&store, &["SET", "a", "3"]);
+        assert_eq!(run(&store, &["DBSIZE"]), ":2\r\n");
+
+        assert_eq!(run(&store, &["flushdb"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["DBSIZE"]), ":0\r\n");
+        assert_eq!(run(&store, &["GET", "a"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn test_command_info() {
+        let store = Store::new();
+        assert_eq!(
+            run(&store, &["COMMAND", "INFO", "get"]),
+            "*1\r\n*6\r\n$3\r\nget\r\n:2\r\n*1\r\n+readonly\r\n:1\r\n:1\r\n:1\r\n"
+        );
+        assert_eq!(
+            run(&store, &["command", "info", "SET", "nope", "mset"]),
+            "*3\r\n\
+             *6\r\n$3\r\nset\r\n:-3\r\n*1\r\n+write\r\n:1\r\n:1\r\n:1\r\n\
+             $-1\r\n\
+             *6\r\n$4\r\nmset\r\n:-3\r\n*1\r\n+write\r\n:1\r\n:-1\r\n:2\r\n"
+        );
+        assert_eq!(run(&store, &["COMMAND", "INFO"]), "*0\r\n");
+        assert!(run(&store, &["COMMAND"]).starts_with(&format!("*{}\r\n", COMMANDS.len())));
+    }
+
+    #[test]
+    fn test_command_specs_parse() {
+        // every command in the table is one the parser knows, with an
+        // arity it accepts
+        for spec in COMMANDS {
+            let n = spec.arity.unsigned_abs() as usize;
+            let mut args: Vec<&[u8]> = vec![spec.name.as_bytes()];
+            args.extend(vec![&b"1"[..]; n - 1]);
+            match spec.name {
+                "OBJECT" => args[1] = b"ENCODING",
+                "DEBUG" => args[1] = b"CHANGE-REPL-ID",
+                "LATENCY" => args[1] = b"LATEST",
+                "MEMORY" => args[1] = b"STATS",
+                "CLIENT" => args[1] = b"ID",
+                _ => {}
+            }
+            let req = RedisValue::from_slices(&args);
+            assert!(parse_command(req.nodes).is_ok(), "{}", spec.name);
         }
     }
+
+    #[test]
+    fn test_get_many() {
+        let store = Store::new();
+        run(&store, &["SET", "a", "1"]);
+        run(&store, &["SET", "c", "3"]);
+
+        let values = store.get_many(&["c", "b", "a", "a"]);
+        assert_eq!(
+            values,
+            vec![
+                Some(Bytes::from("3")),
+                None,
+                Some(Bytes::from("1")),
+                Some(Bytes::from("1")),
+            ]
+        );
+        assert!(store.get_many::<&str>(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_mget() {
+        let store = Store::new();
+        run(&store, &["SET", "a", "1"]);
+        run(&store, &["SET", "c", "3"]);
+
+        assert_eq!(
+            run(&store, &["MGET", "c", "b", "a"]),
+            "*3\r\n$1\r\n3\r\n$-1\r\n$1\r\n1\r\n"
+        );
+        assert_eq!(run(&store, &["MGET", "x"]), "*1\r\n$-1\r\n");
+    }
+
+    #[test]
+    fn test_setnx() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["SETNX", "k", "first"]), ":1\r\n");
+        assert_eq!(run(&store, &["SETNX", "k", "second"]), ":0\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$5\r\nfirst\r\n");
+    }
+
+    #[test]
+    fn test_getset() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["GETSET", "k", "first"]), "$-1\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$5\r\nfirst\r\n");
+        assert_eq!(run(&store, &["GETSET", "k", "second"]), "$5\r\nfirst\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$6\r\nsecond\r\n");
+    }
+
+    #[test]
+    fn test_getdel() {
+        let store = Store::new();
+        run(&store, &["SET", "k", "v"]);
+
+        assert_eq!(run(&store, &["GETDEL", "k"]), "$1\r\nv\r\n");
+        assert_eq!(run(&store, &["GET", "k"]), "$-1\r\n");
+        assert_eq!(run(&store, &["GETDEL", "k"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn test_mset() {
+        let store = Store::new();
+        run(&store, &["SET", "a", "old"]);
+
+        assert_eq!(run(&store, &["MSET", "a", "1", "b", "2"]), "+Ok\r\n");
+        assert_eq!(
+            run(&store, &["MGET", "a", "b"]),
+            "*2\r\n$1\r\n1\r\n$1\r\n2\r\n"
+        );
+        assert_eq!(run(&store, &["MSET", "a", "3", "a", "4"]), "+Ok\r\n");
+        assert_eq!(run(&store, &["GET", "a"]), "$1\r\n4\r\n");
+    }
+
+    #[test]
+    fn test_exists() {
+        let store = Store::new();
+        run(&store, &["SET", "foo", "1"]);
+        run(&store, &["SET", "bar", "2"]);
+
+        assert_eq!(run(&store, &["EXISTS", "foo"]), ":1\r\n");
+        assert_eq!(run(&store, &["EXISTS", "missing"]), ":0\r\n");
+        assert_eq!(run(&store, &["EXISTS", "foo", "foo"]), ":2\r\n");
+        assert_eq!(run(&store, &["EXISTS", "foo", "bar", "missing", "foo"]), ":3\r\n");
+    }
+
+    #[test]
+    fn test_debug_noops() {
+        let store = Store::new();
+
+        for subcommand in DEBUG_NOOPS {
+            let subcommand = str::from_utf8(subcommand).unwrap();
+            assert_eq!(run(&store, &["DEBUG", subcommand]), "+Ok\r\n");
+            assert_eq!(run(&store, &["debug", &subcommand.to_lowercase(), "0"]), "+Ok\r\n");
+        }
+
+        let (_, req) = RedisValue::decode(&"*2\r\n$5\r\nDEBUG\r\n$8\r\nSEGFAULT\r\n")
+            .unwrap()
+            .unwrap();
+        assert!(parse_command(req.nodes).is_err());
+    }
+
+    #[test]
+    fn test_latency_stubs() {
+        let store = Store::new();
+
+        assert_eq!(run(&store, &["LATENCY", "HISTORY", "command"]), "*0\r\n");
+        assert_eq!(run(&store, &["LATENCY", "LATEST"]), "*0\r\n");
+        assert_eq!(run(&store, &["LATENCY", "RESET"]), ":0\r\n");
+        assert_eq!(run(&store, &["latency", "reset", "command", "fork"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_object_encoding() {
+        let store = Store::new();
+        let embstr = "x".repeat(44);
+        let raw = "x".repeat(45);
+
+        run(&store, &["SET", "embstr", &embstr]);
+        run(&store, &["SET", "raw", &raw]);
+        run(&store, &["SET", "int", "12345"]);
+        run(&store, &["SET", "negative", "-42"]);
+        run(&store, &["SET", "padded", "0042"]);
+        run(&store, &["SET", "overflow", "99999999999999999999"]);
+
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "embstr"]), "$6\r\nembstr\r\n");
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "raw"]), "$3\r\nraw\r\n");
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "int"]), "$3\r\nint\r\n");
+        assert_eq!(run(&store, &["object", "encoding", "negative"]), "$3\r\nint\r\n");
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "padded"]), "$6\r\nembstr\r\n");
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "overflow"]), "$6\r\nembstr\r\n");
+        assert_eq!(run(&store, &["OBJECT", "ENCODING", "missing"]), "$-1\r\n");
+    }
 }